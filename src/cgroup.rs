@@ -0,0 +1,131 @@
+//! Reading the accounting of a cgroup v2, for measuring a whole container
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+/// Where the cgroup v2 hierarchy is mounted, which relative cgroup paths start from
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The CPU time a cgroup's processes have used in total, from `cpu.stat`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CpuStat {
+    pub usage_usec: u64,
+    pub user_usec: u64,
+    pub system_usec: u64,
+}
+
+fn parse_cpu_stat(text: &str) -> Result<CpuStat> {
+    let mut stat = CpuStat::default();
+    let mut found = 0;
+    for line in text.lines() {
+        let mut parts = line.split_whitespace();
+        let field = match parts.next() {
+            Some("usage_usec") => &mut stat.usage_usec,
+            Some("user_usec") => &mut stat.user_usec,
+            Some("system_usec") => &mut stat.system_usec,
+            _ => continue,
+        };
+        let value = parts.next().context("Missing value in cpu.stat")?;
+        *field = value
+            .parse()
+            .with_context(|| format!("Invalid value in cpu.stat: {}", value))?;
+        found += 1;
+    }
+    if found < 3 {
+        bail!("cpu.stat is missing usage_usec, user_usec or system_usec");
+    }
+    Ok(stat)
+}
+
+// Cgroup paths from /proc start with a slash but are still relative to the mount point
+fn cgroup_path(path: &Path) -> PathBuf {
+    if path.starts_with(CGROUP_ROOT) {
+        path.to_path_buf()
+    } else {
+        Path::new(CGROUP_ROOT).join(path.strip_prefix("/").unwrap_or(path))
+    }
+}
+
+/// Reads the files of one cgroup in a cgroup v2 hierarchy
+#[derive(Clone, Debug)]
+pub struct CgroupReader {
+    path: PathBuf,
+}
+
+impl CgroupReader {
+    /// `path` is either a full path into /sys/fs/cgroup or a cgroup as /proc/<pid>/cgroup
+    /// shows it, e.g. `/system.slice/foo.service`
+    pub fn new(path: &Path) -> Result<CgroupReader> {
+        let reader = CgroupReader {
+            path: cgroup_path(path),
+        };
+        // Fail early on a path that isn't a cgroup v2 (or has no CPU accounting)
+        reader.cpu_stat()?;
+        Ok(reader)
+    }
+
+    fn read(&self, file: &str) -> Result<String> {
+        let path = self.path.join(file);
+        fs::read_to_string(&path).with_context(|| format!("Could not read {}", path.display()))
+    }
+
+    /// Memory charged to the cgroup in bytes, including page cache and kernel memory
+    pub fn memory_current(&self) -> Result<u64> {
+        let text = self.read("memory.current")?;
+        text.trim()
+            .parse()
+            .with_context(|| format!("Invalid memory.current: {}", text.trim()))
+    }
+
+    pub fn cpu_stat(&self) -> Result<CpuStat> {
+        parse_cpu_stat(&self.read("cpu.stat")?)
+    }
+
+    /// The PIDs of the processes directly in the cgroup
+    pub fn procs(&self) -> Result<Vec<i32>> {
+        self.read("cgroup.procs")?
+            .lines()
+            .map(|line| {
+                line.parse()
+                    .with_context(|| format!("Invalid PID in cgroup.procs: {}", line))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cpu_stat_reads_the_usage_fields() {
+        let text = "usage_usec 2500000\nuser_usec 2000000\nsystem_usec 500000\n\
+                    nr_periods 0\nnr_throttled 0\nthrottled_usec 0\n";
+        assert_eq!(
+            parse_cpu_stat(text).unwrap(),
+            CpuStat {
+                usage_usec: 2_500_000,
+                user_usec: 2_000_000,
+                system_usec: 500_000,
+            }
+        );
+    }
+
+    #[test]
+    fn cgroup_path_resolves_under_the_mount_point() {
+        let expected = Path::new("/sys/fs/cgroup/system.slice/foo.service");
+        assert_eq!(
+            cgroup_path(Path::new("/system.slice/foo.service")),
+            expected
+        );
+        assert_eq!(cgroup_path(Path::new("system.slice/foo.service")), expected);
+        assert_eq!(cgroup_path(expected), expected);
+    }
+
+    #[test]
+    fn parse_cpu_stat_rejects_a_v1_file() {
+        assert!(parse_cpu_stat("user 100\nsystem 50\n").is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Finding the block devices behind a process's open files
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use anyhow::Result;
+use procfs::process::{FDInfo, FDTarget};
+
+/// /proc/diskstats counts in 512-byte sectors, whatever the device's real sector size
+const SECTOR_SIZE: u64 = 512;
+
+/// A device's major and minor numbers
+type DeviceNumber = (i32, i32);
+
+// Splits a st_dev the way glibc's major() and minor() do
+fn device_number(dev: u64) -> DeviceNumber {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major as i32, minor as i32)
+}
+
+// The devices holding the files among the fds. Files on virtual filesystems (pipes, sockets,
+// tmpfs, /proc) have devices that aren't in /proc/diskstats, so they drop out later
+fn backing_devices(fds: &[FDInfo]) -> HashSet<DeviceNumber> {
+    fds.iter()
+        .filter_map(|fd| match &fd.target {
+            // The file may have been closed or deleted since the fd list was read
+            FDTarget::Path(path) => fs::metadata(path).ok(),
+            _ => None,
+        })
+        .map(|metadata| device_number(metadata.dev()))
+        .collect()
+}
+
+// A whole disk backed by hardware, as opposed to a partition (which would count its disk's
+// I/O twice) or a virtual device (loop, device mapper, RAID) stacked on other disks
+fn is_physical(name: &str) -> bool {
+    Path::new("/sys/block").join(name).join("device").exists()
+}
+
+/// Reads and writes on one block device during a sample
+#[derive(Clone, Debug)]
+pub struct DiskSample {
+    /// Kernel name of the device, e.g. `sda1` or `nvme0n1p2`
+    pub name: String,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub read_bps: f64,
+    pub write_bps: f64,
+}
+
+/// The sector counters of every block device, as of the previous sample
+pub(crate) struct DiskCounters {
+    prev: HashMap<DeviceNumber, (u64, u64)>,
+}
+
+impl DiskCounters {
+    pub fn new() -> Result<DiskCounters> {
+        let prev = procfs::diskstats()?
+            .into_iter()
+            .map(|stat| {
+                (
+                    (stat.major, stat.minor),
+                    (stat.sectors_read, stat.sectors_written),
+                )
+            })
+            .collect();
+        Ok(DiskCounters { prev })
+    }
+
+    /// The activity of each device that one of the fds is on. The kernel doesn't account
+    /// block I/O by device per process, so these are totals for the whole device, including
+    /// other processes' I/O
+    pub fn sample(&mut self, fds: &[FDInfo], elapsed: f64) -> Result<Vec<DiskSample>> {
+        let devices = backing_devices(fds);
+        self.sample_matching(elapsed, |number, _| devices.contains(&number))
+    }
+
+    /// The activity of each physical disk, which together is all the I/O reaching storage
+    pub fn sample_physical(&mut self, elapsed: f64) -> Result<Vec<DiskSample>> {
+        self.sample_matching(elapsed, |_, name| is_physical(name))
+    }
+
+    // Every device's counters are updated, but only the matching ones are reported
+    fn sample_matching(
+        &mut self,
+        elapsed: f64,
+        matches: impl Fn(DeviceNumber, &str) -> bool,
+    ) -> Result<Vec<DiskSample>> {
+        let mut disks = Vec::new();
+        for stat in procfs::diskstats()? {
+            let number = (stat.major, stat.minor);
+            let sectors = (stat.sectors_read, stat.sectors_written);
+            let prev = self.prev.insert(number, sectors);
+            // A device attached since the previous sample has nothing to compare against yet
+            if let (true, Some(prev)) = (matches(number, &stat.name), prev) {
+                let bytes_read = sectors.0.saturating_sub(prev.0) * SECTOR_SIZE;
+                let bytes_written = sectors.1.saturating_sub(prev.1) * SECTOR_SIZE;
+                disks.push(DiskSample {
+                    name: stat.name,
+                    bytes_read,
+                    bytes_written,
+                    read_bps: bytes_read as f64 / elapsed,
+                    write_bps: bytes_written as f64 / elapsed,
+                });
+            }
+        }
+        Ok(disks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_number_splits_major_and_minor() {
+        // sda1
+        assert_eq!(device_number(0x801), (8, 1));
+        // nvme0n1p2, whose major doesn't fit in the traditional 8 bits
+        assert_eq!(device_number(0x10302), (259, 2));
+        // A minor number above 255
+        assert_eq!(device_number(0x10_0801), (8, 0x101));
+    }
+}
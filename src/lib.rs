@@ -0,0 +1,1218 @@
+//! Monitor a program's resource usage
+//!
+//! A [`Monitor`] attaches to a running process and, each time [`Monitor::sample`] is called,
+//! measures its CPU, memory, disk I/O, and network usage since the previous call.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use procfs::process::{all_processes, FDInfo, Io, ProcState, Process, Stat, Status};
+use procfs::{page_size, CpuInfo, CpuTime, KernelStats, ProcError, ProcResult};
+
+use disk::DiskCounters;
+use net::{process_connections, CaptureUnavailable, Filter, NetCapture, Traffic};
+use system::SystemCounters;
+
+pub use cgroup::{CgroupReader, CpuStat};
+pub use disk::DiskSample;
+pub use net::{Connection, Flow};
+
+mod cgroup;
+mod disk;
+mod net;
+mod system;
+
+// Logic stolen from htop's LinuxProcessList_scanCPUTime
+// Returns total ticks of CPU
+fn total_cpu_time() -> Result<u64> {
+    Ok(total_ticks(&KernelStats::new()?.total))
+}
+
+fn total_ticks(cpu: &CpuTime) -> u64 {
+    let user = cpu.user - cpu.guest.unwrap_or(0);
+    let nice = cpu.nice - cpu.guest_nice.unwrap_or(0);
+    let total_idle = cpu.idle + cpu.iowait.unwrap_or(0);
+    let total_system = cpu.system + cpu.irq.unwrap_or(0) + cpu.softirq.unwrap_or(0);
+    let total_virt = cpu.guest.unwrap_or(0) + cpu.guest_nice.unwrap_or(0);
+    user + nice + total_system + total_idle + total_virt + cpu.steal.unwrap_or(0)
+}
+
+fn period(ticks: u64, prev_ticks: u64, num_cores: usize) -> f64 {
+    ticks.saturating_sub(prev_ticks) as f64 / num_cores as f64
+}
+
+// Kernel counters only grow, but one can appear to go backwards after PID reuse or a racy
+// read. Like period, treat that as no change rather than underflowing
+fn counter_delta(value: u64, prev_value: u64) -> u64 {
+    value.saturating_sub(prev_value)
+}
+
+/// CPU time spent in user and kernel mode, each as a percentage of the period
+#[derive(Clone, Copy, Debug, Default)]
+struct CpuUsage {
+    user_percent: f64,
+    system_percent: f64,
+}
+
+impl CpuUsage {
+    fn total(&self) -> f64 {
+        self.user_percent + self.system_percent
+    }
+}
+
+// A period of (nearly) zero happens when total_cpu_time didn't advance between samples, and
+// would otherwise give inf or NaN. Anything above every core being busy is measurement noise
+fn cpu_percent(ticks: u64, period: f64, num_cores: usize) -> f64 {
+    if period < f64::EPSILON {
+        return 0.0;
+    }
+    (ticks as f64 / period * 100.0).clamp(0.0, num_cores as f64 * 100.0)
+}
+
+fn cpu_usage(stat: &Stat, prev_stat: &Stat, period: f64, num_cores: usize) -> CpuUsage {
+    let user = stat.utime.saturating_sub(prev_stat.utime);
+    let system = stat.stime.saturating_sub(prev_stat.stime);
+    split_cpu_usage(user, system, period, num_cores)
+}
+
+fn split_cpu_usage(user: u64, system: u64, period: f64, num_cores: usize) -> CpuUsage {
+    let total = cpu_percent(user + system, period, num_cores);
+
+    // Split the clamped total in proportion to the user and system ticks
+    let user_share = if user + system > 0 {
+        user as f64 / (user + system) as f64
+    } else {
+        0.0
+    };
+    CpuUsage {
+        user_percent: total * user_share,
+        system_percent: total * (1.0 - user_share),
+    }
+}
+
+/// What 100% CPU means in a [`Sample`]
+///
+/// Each process's ticks are divided by the ticks one core had available during the period
+/// (all cores' ticks over `num_cores`). `Total` further divides that by the number of cores the
+/// processes may run on, so on an 8-core machine one busy core reads 100% in `Core` mode and
+/// 12.5% in `Total` mode, or 50% for a process pinned to two cores
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CpuMode {
+    /// 100% is one core kept busy, like top, so a process can reach 100% times the cores it
+    /// may run on
+    #[default]
+    Core,
+    /// 100% is every core kept busy
+    Total,
+}
+
+impl CpuMode {
+    // Factor turning a percentage of one core into a percentage in this mode
+    fn scale(self, num_cores: usize) -> f64 {
+        match self {
+            CpuMode::Core => 1.0,
+            CpuMode::Total => 1.0 / num_cores as f64,
+        }
+    }
+}
+
+impl FromStr for CpuMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CpuMode, String> {
+        match s {
+            "core" => Ok(CpuMode::Core),
+            "total" => Ok(CpuMode::Total),
+            _ => Err(String::from("must be core or total")),
+        }
+    }
+}
+
+// How many CPUs any of the processes may run on, which taskset or a cpuset can make fewer
+// than the machine has
+fn allowed_cores(pids: &[i32]) -> Result<usize> {
+    let mut allowed = [false; libc::CPU_SETSIZE as usize];
+    for &pid in pids {
+        // SAFETY: cpu_set_t is a plain bitmask, valid when zeroed
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        // SAFETY: the kernel writes at most the size passed into the set
+        let result =
+            unsafe { libc::sched_getaffinity(pid, mem::size_of::<libc::cpu_set_t>(), &mut set) };
+        if result != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Could not read the CPU affinity of pid {}", pid));
+        }
+        for (cpu, allowed) in allowed.iter_mut().enumerate() {
+            // SAFETY: cpu is below CPU_SETSIZE, so within the set
+            *allowed |= unsafe { libc::CPU_ISSET(cpu, &set) };
+        }
+    }
+    Ok(allowed.iter().filter(|&&allowed| allowed).count())
+}
+
+fn process(pid: i32) -> Result<Process> {
+    Process::new(pid).context(format!("Could not locate process with pid {}", pid))
+}
+
+/// Returned by [`Monitor::sample`] once the monitored process is gone
+#[derive(Debug)]
+pub struct ProcessExited {
+    pub pid: i32,
+}
+
+impl fmt::Display for ProcessExited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "process {} exited", self.pid)
+    }
+}
+
+impl Error for ProcessExited {}
+
+/// Combined resource usage of the monitored processes over one sampling period
+#[derive(Clone, Debug, Default)]
+pub struct Sample {
+    /// Seconds since the previous sample
+    pub elapsed: f64,
+    /// CPU usage as a percentage, of one core or of all of them depending on
+    /// [`Config::cpu_mode`]
+    pub cpu: f64,
+    /// The parts of `cpu` spent in user and kernel mode
+    pub cpu_user: f64,
+    pub cpu_system: f64,
+    /// Resident set size in bytes
+    pub rss: u64,
+    /// Virtual memory size in bytes
+    pub vsz: u64,
+    /// Bytes swapped out, or `None` where the kernel doesn't account for it
+    pub swap: Option<u64>,
+    /// Bytes fetched from and sent to storage
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub read_bps: f64,
+    pub write_bps: f64,
+    /// Bytes passed to read and write syscalls per second, including those served by the page
+    /// cache and those on pipes and sockets
+    pub rchar_bps: f64,
+    pub wchar_bps: f64,
+    /// Bytes of network traffic captured on the process's connections, by direction, or
+    /// `None` when traffic isn't being captured
+    pub net_received: Option<u64>,
+    pub net_sent: Option<u64>,
+    pub net_received_bps: Option<f64>,
+    pub net_sent_bps: Option<f64>,
+    /// Packets dropped by the capture since the previous sample, which the network figures
+    /// are missing
+    pub net_dropped: Option<u64>,
+    pub threads: u64,
+    /// Voluntary and involuntary context switches per second
+    pub vctx_ps: f64,
+    pub nvctx_ps: f64,
+    /// Open file descriptors of any kind, not only sockets
+    pub fd_count: usize,
+    /// Usage of each monitored PID (including its descendants, for a tree), which add up to
+    /// the totals above. Network traffic is only counted in total
+    pub processes: Vec<ProcessSample>,
+    /// Monitored PIDs that exited since the previous sample
+    pub exited: Vec<i32>,
+    /// CPU usage of each thread that ran, busiest first, with [`Config::per_thread`]
+    pub per_thread: Vec<ThreadSample>,
+    /// Traffic on each flow that had any, busiest first, with [`Config::flows`]
+    pub flows: Vec<FlowSample>,
+    /// Activity of the block devices the processes have files open on, with
+    /// [`Config::disks`]
+    pub disks: Vec<DiskSample>,
+    /// What the kernel accounted to [`Config::cgroup`], if set
+    pub cgroup: Option<CgroupSample>,
+}
+
+/// Usage of a whole cgroup during a [`Sample`], which covers memory the per-process figures
+/// miss, like page cache and kernel memory
+#[derive(Clone, Debug, Default)]
+pub struct CgroupSample {
+    /// CPU usage as a percentage, scaled and split like [`Sample::cpu`]
+    pub cpu: f64,
+    pub cpu_user: f64,
+    pub cpu_system: f64,
+    /// Memory charged to the cgroup, in bytes
+    pub memory: u64,
+}
+
+/// Network traffic on one of the flows in a [`Sample`]
+#[derive(Clone, Debug)]
+pub struct FlowSample {
+    pub flow: Flow,
+    pub received_bps: f64,
+    pub sent_bps: f64,
+}
+
+/// CPU usage of one thread of the monitored processes in a [`Sample`]
+#[derive(Clone, Debug, Default)]
+pub struct ThreadSample {
+    /// The process the thread belongs to
+    pub pid: i32,
+    pub tid: i32,
+    /// The thread's name, which is the command name unless the program set one
+    pub name: String,
+    /// CPU usage as a percentage, scaled like [`Sample::cpu`]
+    pub cpu: f64,
+}
+
+/// Resource usage of one of the PIDs in a [`Sample`]
+#[derive(Clone, Debug, Default)]
+pub struct ProcessSample {
+    pub pid: i32,
+    pub cpu: f64,
+    pub rss: u64,
+    pub vsz: u64,
+    pub read_bps: f64,
+    pub write_bps: f64,
+    pub threads: u64,
+    pub fd_count: usize,
+}
+
+/// Running totals over a whole run
+#[derive(Default)]
+pub struct Summary {
+    pub elapsed: f64,
+    pub samples: u64,
+    pub cpu_sum: f64,
+    pub cpu_peak: f64,
+    pub rss_peak: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// `None` if traffic wasn't captured for any of the samples
+    pub net_received: Option<u64>,
+    pub net_sent: Option<u64>,
+    pub net_dropped: Option<u64>,
+}
+
+impl Sample {
+    /// Network traffic in both directions, in bytes per second
+    pub fn net_bps(&self) -> Option<f64> {
+        Some(self.net_received_bps? + self.net_sent_bps?)
+    }
+}
+
+impl Summary {
+    pub fn record(&mut self, sample: &Sample) {
+        self.elapsed += sample.elapsed;
+        self.samples += 1;
+        self.cpu_sum += sample.cpu;
+        self.cpu_peak = self.cpu_peak.max(sample.cpu);
+        self.rss_peak = self.rss_peak.max(sample.rss);
+        self.bytes_read += sample.bytes_read;
+        self.bytes_written += sample.bytes_written;
+        if let (Some(received), Some(sent)) = (sample.net_received, sample.net_sent) {
+            *self.net_received.get_or_insert(0) += received;
+            *self.net_sent.get_or_insert(0) += sent;
+        }
+        if let Some(dropped) = sample.net_dropped {
+            *self.net_dropped.get_or_insert(0) += dropped;
+        }
+    }
+
+    pub fn cpu_avg(&self) -> f64 {
+        // Avoid NaN when the run stopped before the first sample
+        if self.samples > 0 {
+            self.cpu_sum / self.samples as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Summary over {:.1}s ({} samples):",
+            self.elapsed, self.samples
+        )?;
+        writeln!(
+            f,
+            "  CPU: avg {:.1}% peak {:.1}%",
+            self.cpu_avg(),
+            self.cpu_peak
+        )?;
+        writeln!(f, "  Mem: peak RSS {}B", self.rss_peak)?;
+        writeln!(
+            f,
+            "  I/O: read {}B written {}B",
+            self.bytes_read, self.bytes_written
+        )?;
+        match (self.net_received, self.net_sent) {
+            (Some(received), Some(sent)) => write!(f, "  Net: ↓{}B ↑{}B", received, sent)?,
+            _ => write!(f, "  Net: N/A")?,
+        }
+        match self.net_dropped {
+            Some(dropped) if dropped > 0 => write!(f, " ({} packets dropped)", dropped),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Settings for a [`Monitor`]
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Whether 100% CPU is one core or all of them
+    pub cpu_mode: CpuMode,
+    /// Don't capture network traffic at all, which needs no privileges
+    pub no_net: bool,
+    /// Keep [`Monitor::connections`] up to date even with `no_net`, which otherwise leaves it
+    /// empty rather than scanning the sockets every sample
+    pub connections: bool,
+    /// Capture device to count network traffic on, instead of pcap's default
+    pub interface: Option<String>,
+    /// Capture on every device at once rather than a single one
+    pub all_interfaces: bool,
+    /// Include every descendant of the process, aggregated with it
+    pub tree: bool,
+    /// Report the CPU usage of every thread in [`Sample::per_thread`]
+    pub per_thread: bool,
+    /// How many cores the processes can use, for [`CpuMode::Total`] and for capping CPU
+    /// usage. `None` counts the cores their CPU affinity allows
+    pub cpus: Option<usize>,
+    /// Measure the whole machine instead of processes, so that [`Monitor::new`] takes no PIDs.
+    /// Memory is what's in use rather than an RSS, the virtual size is the memory committed,
+    /// I/O is the sum over the physical disks, FDs are every open file, and traffic isn't
+    /// filtered. Syscall I/O and context switches aren't measured
+    pub system: bool,
+    /// A cgroup v2 whose memory and CPU accounting to report in [`Sample::cgroup`]
+    pub cgroup: Option<PathBuf>,
+    /// Report the block devices the processes have files open on in [`Sample::disks`]
+    pub disks: bool,
+    /// Break traffic down by flow in [`Sample::flows`]
+    pub flows: bool,
+    /// Leave out traffic between sockets on this host, which never reaches a network
+    pub no_loopback: bool,
+    /// Also save every captured packet to this pcap file. Not supported with `all_interfaces`
+    pub write_pcap: Option<PathBuf>,
+    /// Bytes captured of each packet. `None` captures just the headers, or whole packets when
+    /// writing a pcap file
+    pub snaplen: Option<i32>,
+    /// Size in bytes of the kernel buffer packets wait in, instead of pcap's default. A bigger
+    /// one drops fewer packets when capturing falls behind
+    pub buffer_size: Option<u64>,
+}
+
+/// A process being monitored, with its counters as of the previous sample
+struct Tracked {
+    process: Process,
+    /// Tells this process apart from a later one that is given the same PID
+    starttime: u64,
+    prev_stat: Stat,
+    prev_io: Io,
+    prev_ctxt_switches: (u64, u64),
+    /// Each thread's counters by TID, when measuring threads
+    prev_tasks: Option<HashMap<i32, Stat>>,
+}
+
+/// What one process used since the previous sample
+#[derive(Default)]
+struct Usage {
+    /// CPU time in user and kernel mode, in clock ticks. These are only turned into a
+    /// percentage once summed, so that the cap applies to a whole tree rather than each process
+    user_ticks: u64,
+    system_ticks: u64,
+    rss: u64,
+    vsz: u64,
+    swap: Option<u64>,
+    bytes_read: u64,
+    bytes_written: u64,
+    chars_read: u64,
+    chars_written: u64,
+    threads: u64,
+    vctx: u64,
+    nvctx: u64,
+    fds: Vec<FDInfo>,
+    per_thread: Vec<ThreadSample>,
+}
+
+impl Usage {
+    fn add(&mut self, other: Usage) {
+        self.user_ticks += other.user_ticks;
+        self.system_ticks += other.system_ticks;
+        self.rss += other.rss;
+        self.vsz += other.vsz;
+        self.swap = match (self.swap, other.swap) {
+            (Some(swap), Some(other_swap)) => Some(swap + other_swap),
+            (swap, other_swap) => swap.or(other_swap),
+        };
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.chars_read += other.chars_read;
+        self.chars_written += other.chars_written;
+        self.threads += other.threads;
+        self.vctx += other.vctx;
+        self.nvctx += other.nvctx;
+        self.fds.extend(other.fds);
+        self.per_thread.extend(other.per_thread);
+    }
+
+    fn cpu(&self, period: f64, num_cores: usize) -> CpuUsage {
+        split_cpu_usage(self.user_ticks, self.system_ticks, period, num_cores)
+    }
+}
+
+// Voluntary and involuntary context switches since the process started
+fn ctxt_switches(status: &Status) -> (u64, u64) {
+    (
+        status.voluntary_ctxt_switches.unwrap_or(0),
+        status.nonvoluntary_ctxt_switches.unwrap_or(0),
+    )
+}
+
+// The live threads of a process, by TID. A thread that exits while the list is read is
+// skipped
+fn task_stats(process: &Process) -> ProcResult<HashMap<i32, Stat>> {
+    Ok(process
+        .tasks()?
+        .flatten()
+        .filter_map(|task| Some((task.tid, task.stat().ok()?)))
+        .collect())
+}
+
+// The process can exit (and be reaped) between any two reads of /proc/<pid>, after which they
+// all fail with NotFound
+fn or_exited<T>(result: ProcResult<T>, pid: i32) -> Result<T> {
+    match result {
+        Ok(value) => Ok(value),
+        Err(ProcError::NotFound(_)) => Err(ProcessExited { pid }.into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl Tracked {
+    fn new(process: Process, per_thread: bool) -> Result<Tracked> {
+        let pid = process.pid;
+        Ok(Tracked {
+            starttime: process.stat.starttime,
+            prev_stat: process.stat.clone(),
+            prev_io: or_exited(process.io(), pid)?,
+            prev_ctxt_switches: ctxt_switches(&or_exited(process.status(), pid)?),
+            prev_tasks: per_thread
+                .then(|| or_exited(task_stats(&process), pid))
+                .transpose()?,
+            process,
+        })
+    }
+
+    // Like a tree's children, threads started since the previous sample count from the next
+    // one on, as does a thread whose TID was reused
+    fn sample_threads(&mut self, period: f64, cores: usize) -> Result<Vec<ThreadSample>> {
+        let prev_tasks = match &mut self.prev_tasks {
+            Some(prev_tasks) => prev_tasks,
+            None => return Ok(Vec::new()),
+        };
+        let pid = self.process.pid;
+        let tasks = or_exited(task_stats(&self.process), pid)?;
+        let threads = tasks
+            .iter()
+            .filter_map(|(&tid, stat)| {
+                let prev = prev_tasks
+                    .get(&tid)
+                    .filter(|prev| prev.starttime == stat.starttime)?;
+                Some(ThreadSample {
+                    pid,
+                    tid,
+                    name: stat.comm.clone(),
+                    cpu: cpu_usage(stat, prev, period, cores).total(),
+                })
+            })
+            .collect();
+        *prev_tasks = tasks;
+        Ok(threads)
+    }
+
+    fn pid(&self) -> i32 {
+        self.process.pid
+    }
+
+    /// Fails with [`ProcessExited`] once the process no longer exists
+    fn sample(&mut self, period: f64, cores: usize, page_size: u64) -> Result<Usage> {
+        // stat() re-fetches the data. Once the process is gone (or only a zombie is left
+        // waiting to be reaped) there is nothing more to monitor. A different start time means
+        // it exited and the kernel handed its PID to a new process
+        let stat = match self.process.stat() {
+            Ok(stat)
+                if stat.state().ok() != Some(ProcState::Zombie)
+                    && stat.starttime == self.starttime =>
+            {
+                stat
+            }
+            Ok(_) | Err(ProcError::NotFound(_)) => {
+                return Err(ProcessExited { pid: self.pid() }.into())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let user_ticks = counter_delta(stat.utime, self.prev_stat.utime);
+        let system_ticks = counter_delta(stat.stime, self.prev_stat.stime);
+        let threads = stat.num_threads as u64;
+        self.prev_stat = stat;
+
+        let pid = self.pid();
+        let statm = or_exited(self.process.statm(), pid)?;
+        let rss = statm.resident * page_size;
+        let vsz = statm.size * page_size;
+
+        let io = or_exited(self.process.io(), pid)?;
+        let bytes_read = counter_delta(io.read_bytes, self.prev_io.read_bytes);
+        let bytes_written = counter_delta(io.write_bytes, self.prev_io.write_bytes);
+        let chars_read = counter_delta(io.rchar, self.prev_io.rchar);
+        let chars_written = counter_delta(io.wchar, self.prev_io.wchar);
+        self.prev_io = io;
+
+        let status = or_exited(self.process.status(), pid)?;
+        let swap = status.vmswap.map(|kb| kb * 1024);
+
+        let (voluntary, nonvoluntary) = ctxt_switches(&status);
+        let vctx = counter_delta(voluntary, self.prev_ctxt_switches.0);
+        let nvctx = counter_delta(nonvoluntary, self.prev_ctxt_switches.1);
+        self.prev_ctxt_switches = (voluntary, nonvoluntary);
+
+        let per_thread = self.sample_threads(period, cores)?;
+
+        Ok(Usage {
+            user_ticks,
+            system_ticks,
+            rss,
+            vsz,
+            swap,
+            bytes_read,
+            bytes_written,
+            chars_read,
+            chars_written,
+            threads,
+            vctx,
+            nvctx,
+            fds: or_exited(self.process.fd(), pid)?,
+            per_thread,
+        })
+    }
+}
+
+/// The command name of a process, as `ps` shows it
+pub fn process_name(pid: i32) -> Result<String> {
+    Ok(process(pid)?.stat.comm)
+}
+
+/// PIDs of the running processes with the given command name
+pub fn find_processes(name: &str) -> Result<Vec<i32>> {
+    Ok(all_processes()?
+        .into_iter()
+        .filter(|process| process.stat.state().ok() != Some(ProcState::Zombie))
+        .filter(|process| process.stat.comm == name)
+        .map(|process| process.pid)
+        .collect())
+}
+
+// Every live descendant of pid, found by following parent links across all processes
+fn descendants(pid: i32) -> Result<Vec<Process>> {
+    let mut children: HashMap<i32, Vec<Process>> = HashMap::new();
+    for process in all_processes()? {
+        if process.stat.state().ok() != Some(ProcState::Zombie) {
+            children.entry(process.stat.ppid).or_default().push(process);
+        }
+    }
+
+    let mut descendants = Vec::new();
+    let mut parents = vec![pid];
+    while let Some(parent) = parents.pop() {
+        for child in children.remove(&parent).unwrap_or_default() {
+            parents.push(child.pid);
+            descendants.push(child);
+        }
+    }
+    Ok(descendants)
+}
+
+// The PIDs without repeats and, when monitoring trees, without any that are in the tree of
+// another, so that no process is counted twice (e.g. the workers of a server found by name)
+fn distinct_roots(pids: &[i32], tree: bool) -> Result<Vec<i32>> {
+    let mut roots: Vec<i32> = Vec::new();
+    for &pid in pids {
+        if !roots.contains(&pid) {
+            roots.push(pid);
+        }
+    }
+    if tree && roots.len() > 1 {
+        let mut nested = HashSet::new();
+        for &root in &roots {
+            nested.extend(descendants(root)?.into_iter().map(|process| process.pid));
+        }
+        roots.retain(|pid| !nested.contains(pid));
+    }
+    Ok(roots)
+}
+
+/// One of the monitored PIDs, along with its descendants when monitoring whole trees
+struct Group {
+    root: Tracked,
+    children: Vec<Tracked>,
+    per_thread: bool,
+}
+
+impl Group {
+    fn new(root: Process, tree: bool, per_thread: bool) -> Result<Group> {
+        let mut group = Group {
+            root: Tracked::new(root, per_thread)?,
+            children: Vec::new(),
+            per_thread,
+        };
+        if tree {
+            group.refresh_tree()?;
+        }
+        Ok(group)
+    }
+
+    fn pid(&self) -> i32 {
+        self.root.pid()
+    }
+
+    fn fds(&self) -> Result<Vec<FDInfo>> {
+        let mut fds = self.root.process.fd()?;
+        for child in &self.children {
+            // Children are monitored on a best-effort basis, since they can exit at any time
+            fds.extend(child.process.fd().unwrap_or_default());
+        }
+        Ok(fds)
+    }
+
+    // Picks up children forked since the previous sample and forgets those that exited.
+    // New children start from their current counters, so they only count from here on
+    fn refresh_tree(&mut self) -> Result<()> {
+        let mut previous: HashMap<_, _> = self
+            .children
+            .drain(..)
+            .map(|child| (child.pid(), child))
+            .collect();
+
+        self.children = descendants(self.pid())?
+            .into_iter()
+            .filter_map(|process| match previous.remove(&process.pid) {
+                Some(child) if child.starttime == process.stat.starttime => Some(child),
+                _ => Tracked::new(process, self.per_thread).ok(),
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Fails with [`ProcessExited`] once the root process no longer exists
+    fn sample(&mut self, tree: bool, period: f64, cores: usize, page_size: u64) -> Result<Usage> {
+        let mut usage = self.root.sample(period, cores, page_size)?;
+        if tree {
+            self.refresh_tree()?;
+            for child in &mut self.children {
+                // A child that exits mid-sample is dropped at the next refresh
+                if let Ok(child_usage) = child.sample(period, cores, page_size) {
+                    usage.add(child_usage);
+                }
+            }
+        }
+        Ok(usage)
+    }
+}
+
+// Traffic on each flow since the previous sample, busiest first
+fn flow_samples(traffic: &Traffic, elapsed: f64) -> Vec<FlowSample> {
+    let mut flows: Vec<_> = traffic
+        .flows
+        .iter()
+        .map(|(&flow, bytes)| FlowSample {
+            flow,
+            received_bps: bytes.received as f64 / elapsed,
+            sent_bps: bytes.sent as f64 / elapsed,
+        })
+        .collect();
+    flows.sort_by(|a, b| (b.received_bps + b.sent_bps).total_cmp(&(a.received_bps + a.sent_bps)));
+    flows
+}
+
+// The cores the processes can use, with `cpus` overriding their affinity. The whole machine
+// (in system mode, with no processes) can use all of them
+fn available_cores(pids: &[i32], cpus: Option<usize>, cores: usize) -> Result<usize> {
+    match cpus {
+        Some(cpus) => Ok(cpus),
+        None if pids.is_empty() => Ok(cores),
+        None => Ok(allowed_cores(pids)?.min(cores)),
+    }
+}
+
+/// Measures the combined resource usage of one or more processes, and optionally of all
+/// their descendants
+pub struct Monitor {
+    groups: Vec<Group>,
+    tree: bool,
+    per_thread: bool,
+    no_loopback: bool,
+    /// Whether the sockets are looked up, for the capture filter or [`Monitor::connections`]
+    scan_connections: bool,
+    disks: Option<DiskCounters>,
+    /// Set in [`Config::system`] mode, which has no processes
+    system: Option<SystemCounters>,
+    cgroup: Option<(CgroupReader, CpuStat)>,
+    page_size: u64,
+    /// Cores in the machine, which all of the ticks in /proc/stat are spread over
+    cores: usize,
+    /// Cores the processes may run on, at most `cores`
+    allowed_cores: usize,
+    cpus: Option<usize>,
+    cpu_mode: CpuMode,
+    connections: Vec<Connection>,
+    filter: Filter,
+    /// `None` when capturing failed to start, with the reason in `capture_error`
+    capture: Option<NetCapture>,
+    capture_error: Option<anyhow::Error>,
+    prev_total_ticks: u64,
+    prev_traffic: Traffic,
+    prev_instant: Instant,
+}
+
+impl Monitor {
+    /// Attaches to the processes with the given PIDs and starts capturing their network traffic
+    ///
+    /// Capturing usually needs root or CAP_NET_RAW. If no capture device can be opened,
+    /// everything else is still monitored, and [`Monitor::capture_error`] tells why, unless
+    /// one of the capture options was set. Invalid ones, such as an unknown interface, are
+    /// always errors
+    pub fn new(pids: &[i32], config: &Config) -> Result<Monitor> {
+        let cores = CpuInfo::new()?.num_cores();
+        let pids = &distinct_roots(pids, config.tree)?;
+        let scan_connections = !config.no_net || config.connections;
+        let mut groups = Vec::new();
+        let mut fds = Vec::new();
+        for &pid in pids {
+            let group = Group::new(process(pid)?, config.tree, config.per_thread)?;
+            fds.extend(group.fds()?);
+            groups.push(group);
+        }
+
+        let connections = if scan_connections {
+            process_connections(fds)?
+        } else {
+            Vec::new()
+        };
+        let (filter, capture, capture_error) = if config.no_net {
+            (Filter::default(), None, None)
+        } else {
+            let filter = if config.system {
+                Filter::everything(config.no_loopback)
+            } else {
+                Ok(Filter::new(&connections, config.no_loopback))
+            };
+            let (filter, started) = match filter {
+                Ok(filter) => {
+                    let started = NetCapture::start(&filter, config);
+                    (filter, started)
+                }
+                Err(e) => (Filter::default(), Err(e)),
+            };
+            // Going without traffic is only fine when nothing about capturing was asked for.
+            // An option that was, but can't be honoured, is an error
+            let requested = config.interface.is_some()
+                || config.all_interfaces
+                || config.write_pcap.is_some()
+                || config.snaplen.is_some()
+                || config.buffer_size.is_some();
+            match started {
+                Ok(capture) => (filter, Some(capture), None),
+                Err(e) if e.is::<CaptureUnavailable>() && !requested => (filter, None, Some(e)),
+                Err(e) => return Err(e),
+            }
+        };
+
+        Ok(Monitor {
+            groups,
+            tree: config.tree,
+            per_thread: config.per_thread,
+            no_loopback: config.no_loopback,
+            scan_connections,
+            disks: config.disks.then(DiskCounters::new).transpose()?,
+            system: config.system.then(SystemCounters::new).transpose()?,
+            cgroup: match &config.cgroup {
+                Some(path) => {
+                    let reader = CgroupReader::new(path)?;
+                    let stat = reader.cpu_stat()?;
+                    Some((reader, stat))
+                }
+                None => None,
+            },
+            page_size: page_size()? as u64,
+            cores,
+            allowed_cores: available_cores(pids, config.cpus, cores)?,
+            cpus: config.cpus,
+            cpu_mode: config.cpu_mode,
+            connections,
+            filter,
+            capture,
+            capture_error,
+            prev_total_ticks: total_cpu_time()?,
+            prev_traffic: Traffic::default(),
+            prev_instant: Instant::now(),
+        })
+    }
+
+    /// Starts monitoring more processes, typically replacements for ones that exited. They
+    /// count from the next sample on, and their sockets are added to the capture filter
+    ///
+    /// Processes that have exited by now are skipped, and the PIDs that were attached are
+    /// returned. Fails with [`ProcessExited`] if every one of them is gone
+    pub fn attach(&mut self, pids: &[i32]) -> Result<Vec<i32>> {
+        // Leaving out processes that are already monitored, on their own or in a tree
+        let monitored = self.pids();
+        let mut candidates = monitored.clone();
+        candidates.extend(pids);
+        let roots = distinct_roots(&candidates, self.tree)?;
+        let mut groups = Vec::new();
+        let mut exited = None;
+        for &pid in pids {
+            if !roots.contains(&pid) || monitored.contains(&pid) {
+                continue;
+            }
+            let group = or_exited(Process::new(pid), pid)
+                .and_then(|process| Group::new(process, self.tree, self.per_thread));
+            match group {
+                Ok(group) => groups.push(group),
+                Err(e) if e.is::<ProcessExited>() => exited = Some(e),
+                Err(e) => return Err(e),
+            }
+        }
+        if let (true, Some(e)) = (groups.is_empty(), exited) {
+            return Err(e);
+        }
+
+        // When every earlier process is gone, the next sample should only cover the time
+        // since the new ones were attached, leaving out the traffic captured in between
+        if self.groups.is_empty() {
+            self.prev_instant = Instant::now();
+            self.prev_total_ticks = total_cpu_time()?;
+            if let Some(capture) = &self.capture {
+                self.prev_traffic = capture.traffic_at(SystemTime::now());
+            }
+        }
+        let attached = groups.iter().map(Group::pid).collect();
+        self.groups.extend(groups);
+        self.allowed_cores = available_cores(&self.pids(), self.cpus, self.cores)?;
+
+        if !self.scan_connections {
+            return Ok(attached);
+        }
+        let mut fds = Vec::new();
+        for group in &self.groups {
+            fds.extend(group.fds()?);
+        }
+        self.connections = process_connections(fds)?;
+        if let Some(capture) = &self.capture {
+            self.filter = Filter::new(&self.connections, self.no_loopback);
+            capture.set_filter(self.filter.clone())?;
+        }
+        Ok(attached)
+    }
+
+    /// Why network traffic isn't being captured, if it isn't
+    pub fn capture_error(&self) -> Option<&anyhow::Error> {
+        self.capture_error.as_ref()
+    }
+
+    /// The highest CPU usage a sample can read: 100% for each core the processes may run on,
+    /// or 100% in all with [`CpuMode::Total`]
+    pub fn cpu_max(&self) -> f64 {
+        100.0 * self.allowed_cores as f64 * self.cpu_mode.scale(self.allowed_cores)
+    }
+
+    /// The monitored PIDs that are still running
+    pub fn pids(&self) -> Vec<i32> {
+        self.groups.iter().map(Group::pid).collect()
+    }
+
+    /// The TCP and UDP sockets of the monitored processes, as of the latest sample. Empty with
+    /// [`Config::no_net`], unless [`Config::connections`] is set
+    pub fn connections(&self) -> &[Connection] {
+        &self.connections
+    }
+
+    /// The BPF filter currently selecting the processes' network traffic, empty when it isn't
+    /// being captured
+    pub fn filter(&self) -> &str {
+        &self.filter.program
+    }
+
+    /// Measures usage since the previous sample (or since the monitor was created)
+    ///
+    /// Processes that exit are left out of later samples. Fails with [`ProcessExited`] once
+    /// none of them exist anymore.
+    pub fn sample(&mut self) -> Result<Sample> {
+        // Rates are computed over the time that actually passed, which can exceed the
+        // requested interval if the sleep or the previous sample overran
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.prev_instant).as_secs_f64();
+        self.prev_instant = now;
+        // Packets are counted up to this time by their capture timestamps, which are wall-clock
+        let boundary = SystemTime::now();
+
+        let total_ticks = total_cpu_time()?;
+        let period = period(total_ticks, self.prev_total_ticks, self.cores);
+        self.prev_total_ticks = total_ticks;
+
+        let mut usage = Usage::default();
+        let mut processes = Vec::new();
+        let mut exited = Vec::new();
+        let mut error = None;
+        let (tree, cores, page_size) = (self.tree, self.allowed_cores, self.page_size);
+        let cpu_scale = self.cpu_mode.scale(cores);
+        self.groups
+            .retain_mut(|group| match group.sample(tree, period, cores, page_size) {
+                Ok(group_usage) => {
+                    processes.push(ProcessSample {
+                        pid: group.pid(),
+                        cpu: group_usage.cpu(period, cores).total() * cpu_scale,
+                        rss: group_usage.rss,
+                        vsz: group_usage.vsz,
+                        read_bps: group_usage.bytes_read as f64 / elapsed,
+                        write_bps: group_usage.bytes_written as f64 / elapsed,
+                        threads: group_usage.threads,
+                        fd_count: group_usage.fds.len(),
+                    });
+                    usage.add(group_usage);
+                    true
+                }
+                Err(e) if e.is::<ProcessExited>() => {
+                    exited.push(group.pid());
+                    false
+                }
+                Err(e) => {
+                    error.get_or_insert(e);
+                    true
+                }
+            });
+        if let Some(e) = error {
+            return Err(e);
+        }
+        if let (true, Some(&pid)) = (self.groups.is_empty(), exited.last()) {
+            return Err(ProcessExited { pid }.into());
+        }
+        let mut open_files = None;
+        if let Some(system) = &mut self.system {
+            let (system_usage, files) = system.sample(elapsed)?;
+            usage = system_usage;
+            open_files = Some(files);
+        }
+        let cpu = usage.cpu(period, cores);
+
+        let (net, flows) = match &self.capture {
+            Some(capture) => {
+                let traffic = capture.traffic_at(boundary);
+                let net_received = traffic.total.received - self.prev_traffic.total.received;
+                let net_sent = traffic.total.sent - self.prev_traffic.total.sent;
+                let dropped = traffic.dropped - self.prev_traffic.dropped;
+                let flows = flow_samples(&traffic, elapsed);
+                self.prev_traffic = traffic;
+                (Some((net_received, net_sent, dropped)), flows)
+            }
+            None => (None, Vec::new()),
+        };
+
+        let fd_count = open_files.unwrap_or(usage.fds.len());
+        let mut per_thread = std::mem::take(&mut usage.per_thread);
+        for thread in &mut per_thread {
+            thread.cpu *= cpu_scale;
+        }
+        per_thread.sort_by(|a, b| b.cpu.total_cmp(&a.cpu));
+        let cgroup = match &mut self.cgroup {
+            Some((reader, prev_stat)) => {
+                let stat = reader.cpu_stat()?;
+                let percent = |usec: u64, prev_usec: u64| {
+                    counter_delta(usec, prev_usec) as f64 / (elapsed * 1e4) * cpu_scale
+                };
+                let sample = CgroupSample {
+                    cpu: percent(stat.usage_usec, prev_stat.usage_usec),
+                    cpu_user: percent(stat.user_usec, prev_stat.user_usec),
+                    cpu_system: percent(stat.system_usec, prev_stat.system_usec),
+                    memory: reader.memory_current()?,
+                };
+                *prev_stat = stat;
+                Some(sample)
+            }
+            None => None,
+        };
+
+        let disks = match &mut self.disks {
+            Some(counters) if self.system.is_some() => counters.sample_physical(elapsed)?,
+            Some(counters) => counters.sample(&usage.fds, elapsed)?,
+            None => Vec::new(),
+        };
+
+        // Recompiling the filter is only worth it when a process opened or closed a socket.
+        // A socket changing state (e.g. to CLOSE_WAIT) leaves the filter as it is
+        if self.scan_connections {
+            let connections = process_connections(usage.fds)?;
+            if let (true, Some(capture)) = (connections != self.connections, &self.capture) {
+                let filter = Filter::new(&connections, self.no_loopback);
+                if filter.program != self.filter.program {
+                    self.filter = filter;
+                    capture.set_filter(self.filter.clone())?;
+                }
+            }
+            self.connections = connections;
+        }
+
+        Ok(Sample {
+            elapsed,
+            cpu: cpu.total() * cpu_scale,
+            cpu_user: cpu.user_percent * cpu_scale,
+            cpu_system: cpu.system_percent * cpu_scale,
+            rss: usage.rss,
+            vsz: usage.vsz,
+            swap: usage.swap,
+            bytes_read: usage.bytes_read,
+            bytes_written: usage.bytes_written,
+            read_bps: usage.bytes_read as f64 / elapsed,
+            write_bps: usage.bytes_written as f64 / elapsed,
+            rchar_bps: usage.chars_read as f64 / elapsed,
+            wchar_bps: usage.chars_written as f64 / elapsed,
+            net_received: net.map(|(received, _, _)| received),
+            net_sent: net.map(|(_, sent, _)| sent),
+            net_received_bps: net.map(|(received, _, _)| received as f64 / elapsed),
+            net_sent_bps: net.map(|(_, sent, _)| sent as f64 / elapsed),
+            net_dropped: net.map(|(_, _, dropped)| dropped),
+            threads: usage.threads,
+            vctx_ps: usage.vctx as f64 / elapsed,
+            nvctx_ps: usage.nvctx as f64 / elapsed,
+            fd_count,
+            processes,
+            exited,
+            per_thread,
+            flows,
+            disks,
+            cgroup,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn period_saturates_when_ticks_go_backwards() {
+        assert_eq!(period(120, 20, 4), 25.0);
+        assert_eq!(period(20, 120, 4), 0.0);
+    }
+
+    #[test]
+    fn counter_delta_is_zero_when_counter_decreases() {
+        assert_eq!(counter_delta(4096, 1024), 3072);
+        assert_eq!(counter_delta(1024, 4096), 0);
+    }
+
+    #[test]
+    fn cpu_percent_is_zero_for_zero_period() {
+        assert_eq!(cpu_percent(50, 0.0, 4), 0.0);
+        assert_eq!(cpu_percent(0, period(20, 20, 4), 4), 0.0);
+    }
+
+    #[test]
+    fn cpu_percent_is_clamped_to_all_cores() {
+        assert_eq!(cpu_percent(5, 10.0, 4), 50.0);
+        assert_eq!(cpu_percent(1000, 10.0, 4), 400.0);
+    }
+
+    #[test]
+    fn tree_cpu_is_capped_as_a_whole() {
+        // Two processes each busy for 80% of the one core's period
+        let child = || Usage {
+            user_ticks: 80,
+            ..Usage::default()
+        };
+        let mut tree = child();
+        tree.add(child());
+        assert_eq!(tree.cpu(period(100, 0, 1), 1).total(), 100.0);
+    }
+
+    #[test]
+    fn total_mode_divides_by_the_cores() {
+        // One core kept busy for the whole period on an 8-core machine
+        let busy_core = cpu_percent(100, period(800, 0, 8), 8);
+        assert_eq!(busy_core * CpuMode::Core.scale(8), 100.0);
+        assert_eq!(busy_core * CpuMode::Total.scale(8), 12.5);
+    }
+
+    #[test]
+    fn or_exited_turns_a_missing_process_into_process_exited() {
+        let gone: ProcResult<()> = Err(ProcError::NotFound(None));
+        let e = or_exited(gone, 42).unwrap_err();
+        assert_eq!(e.downcast_ref::<ProcessExited>().unwrap().pid, 42);
+        assert_eq!(or_exited(Ok(7), 42).unwrap(), 7);
+    }
+
+    #[test]
+    fn repeated_pids_are_counted_once() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let pid = child.id() as i32;
+        // Its memory keeps changing while it starts up, until it settles into sleeping
+        let mut last = None;
+        loop {
+            let process = Process::new(pid).unwrap();
+            let statm = process.statm().unwrap();
+            let memory = Some((statm.size, statm.resident));
+            if process.stat.state().unwrap() == ProcState::Sleeping && memory == last {
+                break;
+            }
+            last = memory;
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        let config = Config {
+            no_net: true,
+            ..Config::default()
+        };
+        let once = Monitor::new(&[pid], &config).unwrap().sample().unwrap();
+        let twice = Monitor::new(&[pid, pid], &config)
+            .unwrap()
+            .sample()
+            .unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert_eq!(twice.processes.len(), 1);
+        assert_eq!(
+            (twice.rss, twice.vsz, twice.threads, twice.fd_count),
+            (once.rss, once.vsz, once.threads, once.fd_count)
+        );
+    }
+
+    #[test]
+    fn attach_skips_processes_that_already_exited() {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let gone = child.id() as i32;
+        child.wait().unwrap();
+        let config = Config {
+            no_net: true,
+            ..Config::default()
+        };
+        let mut monitor = Monitor::new(&[], &config).unwrap();
+
+        assert!(monitor.attach(&[gone]).unwrap_err().is::<ProcessExited>());
+        let me = std::process::id() as i32;
+        assert_eq!(monitor.attach(&[gone, me]).unwrap(), vec![me]);
+        assert_eq!(monitor.pids(), vec![me]);
+    }
+
+    #[test]
+    fn available_cores_prefers_the_override() {
+        let pid = std::process::id() as i32;
+        assert_eq!(available_cores(&[pid], Some(2), 8).unwrap(), 2);
+        assert_eq!(available_cores(&[], None, 8).unwrap(), 8);
+        let allowed = available_cores(&[pid], None, 8).unwrap();
+        assert!((1..=8).contains(&allowed));
+    }
+}
@@ -1,143 +1,897 @@
-use std::collections::HashSet;
-use std::env;
-use std::sync::{mpsc, Arc, Mutex};
-use std::{thread, time::Duration};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
+use log::{info, warn, LevelFilter};
 use pcap::Device;
-use procfs::net::TcpNetEntry;
-use procfs::process::FDInfo;
-use procfs::{page_size, process::FDTarget, process::Process, process::Stat, CpuInfo, KernelStats};
-
-// Logic stolen from htop's LinuxProcessList_scanCPUTime
-// Returns total ticks of CPU
-fn total_cpu_time() -> Result<u64> {
-    let cpu = KernelStats::new()?.total;
-    let user = cpu.user - cpu.guest.unwrap_or(0);
-    let nice = cpu.nice - cpu.guest_nice.unwrap_or(0);
-    let total_idle = cpu.idle + cpu.iowait.unwrap_or(0);
-    let total_system = cpu.system + cpu.irq.unwrap_or(0) + cpu.softirq.unwrap_or(0);
-    let total_virt = cpu.guest.unwrap_or(0) + cpu.guest_nice.unwrap_or(0);
-    Ok(user + nice + total_system + total_idle + total_virt + cpu.steal.unwrap_or(0))
-}
-
-fn period(ticks: u64, prev_ticks: u64, num_cores: usize) -> f64 {
-    ticks.saturating_sub(prev_ticks) as f64 / num_cores as f64
-}
-
-fn cpu_usage(stat: &Stat, prev_stat: &Stat, period: f64) -> f64 {
-    ((stat.utime + stat.stime) - (prev_stat.utime + prev_stat.stime)) as f64 / period * 100.0
-}
-
-fn process(pid: i32) -> Result<Process> {
-    Process::new(pid).context(format!("Could not locate process with pid {}", pid))
-}
-
-// Create a Berkley Packet Filter to find packets belonging to one of the ports in use by the process
-// Packets are considered a match if they have the same protocol, host address, and destination address
-// Therefore, we create a filter like:
-// (host 127.0.0.1 and host 127.0.0.1 and port 33791 and port 60914) or (...)
-fn build_packet_filter<F, T>(fd: F, tcp: T) -> String
-where
-    F: IntoIterator<Item = FDInfo>,
-    T: IntoIterator<Item = TcpNetEntry>,
-{
-    // Given a list of file descriptors, find the inodes of those that are sockets
-    let inodes: HashSet<_> = fd
-        .into_iter()
-        .filter_map(|fd| match fd.target {
-            FDTarget::Socket(inode) => Some(inode),
-            _ => None,
-        })
-        .collect();
-
-    // Add to the filter each TCP entry that corresponds to a socket in the fd list
-    tcp.into_iter()
-        .filter(|entry| inodes.contains(&entry.inode))
-        .map(|entry| {
-            format!(
-                "(host {} and host {} and port {} and port {})",
-                entry.local_address.ip(),
-                entry.remote_address.ip(),
-                entry.local_address.port(),
-                entry.remote_address.port()
-            )
-        })
-        .collect::<Vec<_>>()
-        .join(" or ")
+use resolve::Resolver;
+use usg::{
+    find_processes, process_name, CgroupReader, Config, Connection, CpuMode, Monitor,
+    ProcessExited, Sample, Summary,
+};
+
+use output::{Format, Outputs, SampleWriter};
+
+mod output;
+mod prometheus;
+mod resolve;
+mod tui;
+
+/// Monitor a program's resource usage
+#[derive(Parser)]
+#[clap(version, arg_required_else_help = true)]
+struct Args {
+    /// PID of a process to monitor. Repeat it (or separate PIDs with commas) to monitor several
+    /// processes as one
+    #[clap(
+        long = "pid",
+        value_name = "PID",
+        value_parser = parse_pid,
+        value_delimiter = ',',
+        required_unless_present_any = &["list-interfaces", "cgroup", "name", "system"]
+    )]
+    pids: Vec<i32>,
+
+    /// Monitor the processes with this command name (as ps shows it) instead of given PIDs
+    #[clap(long, value_name = "NAME", conflicts_with_all = &["pids", "cgroup"])]
+    name: Option<String>,
+
+    /// Monitor the whole machine instead of processes. RSS is then the memory in use, VSZ the
+    /// memory committed, I/O the total over the physical disks, and FDs every open file, and
+    /// all network traffic is counted
+    #[clap(
+        long,
+        conflicts_with_all = &["pids", "name", "cgroup", "tree", "follow-name", "per-pid", "threads"]
+    )]
+    system: bool,
+
+    /// With --name, wait for a matching process to start, and whenever they have all exited,
+    /// for another one to attach to instead of stopping
+    #[clap(long, requires = "name")]
+    follow: bool,
+
+    /// Also report the memory and CPU accounted to this cgroup v2, given as /proc/<pid>/cgroup
+    /// shows it or as a path under /sys/fs/cgroup. Without --pid, monitors the processes in it
+    #[clap(long, value_name = "PATH")]
+    cgroup: Option<PathBuf>,
+
+    /// What 100% CPU means: "core" is one core kept busy, like top, so a multithreaded process
+    /// can go above 100%. "total" is every core kept busy
+    #[clap(long, value_name = "MODE", default_value = "core", value_parser)]
+    cpu_mode: CpuMode,
+
+    /// Number of cores the processes can use, for --cpu-mode total and for capping CPU usage.
+    /// By default, the cores their CPU affinity (e.g. from taskset or a cpuset) allows
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    cpus: Option<u64>,
+
+    /// Split CPU usage into time spent in user and kernel mode
+    #[clap(long)]
+    cpu_breakdown: bool,
+
+    /// Also print the usage of each PID, when monitoring several
+    #[clap(long)]
+    per_pid: bool,
+
+    /// Seconds to wait between samples
+    #[clap(long, default_value = "1", value_parser = parse_seconds)]
+    interval: f64,
+
+    /// Stop after monitoring for this many seconds and print averages
+    #[clap(long, value_parser = parse_seconds)]
+    duration: Option<f64>,
+
+    /// Don't capture network traffic, so no privileges are needed. Only CPU, memory and I/O
+    /// are reported
+    #[clap(
+        long,
+        conflicts_with_all = &["interface", "all-interfaces", "write-pcap", "snaplen", "buffer-size"]
+    )]
+    no_net: bool,
+
+    /// Capture device to count network traffic on, instead of pcap's default
+    #[clap(long, value_name = "NAME")]
+    interface: Option<String>,
+
+    /// Capture on every device at once, for processes using several interfaces
+    #[clap(long, conflicts_with = "interface")]
+    all_interfaces: bool,
+
+    /// Don't count traffic with other processes on this host, e.g. a local database
+    #[clap(long)]
+    no_loopback: bool,
+
+    /// Also save the captured packets to this pcap file, e.g. to open in Wireshark
+    #[clap(long, value_name = "PATH", conflicts_with = "all-interfaces")]
+    write_pcap: Option<PathBuf>,
+
+    /// Bytes to capture of each packet [default: 96, or whole packets with --write-pcap]. Only
+    /// the headers are needed to count traffic
+    #[clap(long, value_name = "BYTES", value_parser = clap::value_parser!(i32).range(1..))]
+    snaplen: Option<i32>,
+
+    /// Size of the kernel buffer for captured packets, e.g. 16MiB. Raise it if the capture
+    /// drops packets
+    #[clap(long, value_name = "SIZE", value_parser = parse_size)]
+    buffer_size: Option<u64>,
+
+    /// When the monitored processes have all exited, attach to running processes with the
+    /// same name instead of stopping, e.g. to keep watching a restarted server
+    #[clap(long)]
+    follow_name: bool,
+
+    /// Also monitor every descendant of the process, aggregated with it
+    #[clap(long)]
+    tree: bool,
+
+    /// Also list the processes' TCP and UDP connections after each sample
+    #[clap(long)]
+    connections: bool,
+
+    /// Show the hostnames of remote peers in the connection list
+    #[clap(long, requires = "connections")]
+    resolve: bool,
+
+    /// Also show the bytes passed to read and write syscalls, next to those that reached
+    /// storage. Much more of the former means reads are served from the page cache
+    #[clap(long)]
+    syscall_io: bool,
+
+    /// Also show the read and write rates of the block devices the process has files open
+    /// on (or with --system, of every physical disk). These count every process's I/O to the
+    /// device, not only this one's
+    #[clap(long)]
+    disks: bool,
+
+    /// Also list the N threads using the most CPU after each sample
+    #[clap(long, value_name = "N")]
+    threads: Option<usize>,
+
+    /// Also list the busiest N connections by bandwidth after each sample
+    #[clap(long, value_name = "N")]
+    top_connections: Option<usize>,
+
+    /// Also write every sample to this file, in the format given by --format
+    #[clap(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Format of the samples written by --output
+    #[clap(long, value_enum, default_value = "json")]
+    format: Format,
+
+    /// Serve the latest sample as Prometheus metrics on this address, e.g. 127.0.0.1:9100
+    #[clap(long, value_name = "ADDR")]
+    prometheus: Option<SocketAddr>,
+
+    /// When to color values that cross a threshold
+    #[clap(long, value_enum, value_name = "WHEN", default_value = "auto")]
+    color: ColorChoice,
+
+    /// RSS above which the memory figure is colored, e.g. 512MiB
+    #[clap(long, value_name = "SIZE", default_value = "1GiB", value_parser = parse_size)]
+    color_mem: u64,
+
+    /// Warn when CPU usage goes above this percentage (as set by --cpu-mode)
+    #[clap(long, value_name = "PERCENT", value_parser = parse_percent)]
+    alert_cpu: Option<f64>,
+
+    /// Warn when RSS goes above this size, e.g. 2GiB
+    #[clap(long, value_name = "SIZE", value_parser = parse_size)]
+    alert_mem: Option<u64>,
+
+    /// Warn when network traffic goes above this many bytes per second, e.g. 100MiB
+    #[clap(long, value_name = "SIZE", value_parser = parse_size)]
+    alert_net: Option<u64>,
+
+    /// Stop and exit with a failure status as soon as an alert fires
+    #[clap(long)]
+    alert_exit: bool,
+
+    /// Smooth the printed CPU, I/O and network rates with an exponential moving average,
+    /// weighting each new sample by ALPHA (between 0 and 1). Files and metrics get the raw values
+    #[clap(long, value_name = "ALPHA", value_parser = parse_alpha)]
+    smooth: Option<f64>,
+
+    /// Append sparklines of recent CPU and network usage to each line
+    #[clap(long)]
+    graph: bool,
+
+    /// Show live gauges in a full-screen view instead of printing lines. Press q to quit
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "follow", "follow-name", "per-pid", "cpu-breakdown", "connections", "syscall-io",
+            "disks", "threads", "top-connections", "color", "color-mem", "alert-cpu",
+            "alert-mem", "alert-net", "alert-exit", "smooth", "graph", "verbose"
+        ]
+    )]
+    tui: bool,
+
+    /// List the available capture devices and exit
+    #[clap(long)]
+    list_interfaces: bool,
+
+    /// Print the BPF filter selecting the processes' traffic before the first sample
+    #[clap(long, conflicts_with = "no-net")]
+    print_filter: bool,
+
+    /// Log what is being monitored (including the initial filter) to stderr, and with -vv every
+    /// filter change and the capture statistics. RUST_LOG takes precedence when set
+    #[clap(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
 }
 
-fn main() -> Result<()> {
-    let pid: i32 = env::args().collect::<Vec<String>>()[1].parse().unwrap();
+#[derive(Clone, Copy, ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
 
-    let page_size = page_size()?;
-    let cores = CpuInfo::new()?.num_cores();
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => io::stdout().is_terminal(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+fn parse_pid(s: &str) -> Result<i32, String> {
+    match s.parse::<i32>() {
+        Ok(pid) if pid > 0 => Ok(pid),
+        _ => Err(String::from("pid must be a positive integer")),
+    }
+}
 
-    let process = process(pid)?;
-    let mut prev_stat = process.stat.clone();
-    let mut prev_total_ticks = total_cpu_time()?;
+// Also rejects values too large for a Duration, which sleeping on would panic
+fn parse_seconds(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs > 0.0 && Duration::try_from_secs_f64(secs).is_ok() => Ok(secs),
+        _ => Err(String::from("must be a positive number of seconds")),
+    }
+}
+
+fn parse_alpha(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(alpha) if alpha > 0.0 && alpha <= 1.0 => Ok(alpha),
+        _ => Err(String::from("must be a number above 0 and at most 1")),
+    }
+}
 
-    let io = process.io()?;
-    let (mut prev_bytes_read, mut prev_bytes_written) = (io.read_bytes, io.write_bytes);
+fn parse_percent(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(percent) if percent.is_finite() && percent >= 0.0 => Ok(percent),
+        _ => Err(String::from("must be a non-negative percentage")),
+    }
+}
 
-    let bpf = build_packet_filter(process.fd()?, procfs::net::tcp()?);
-    println!("{bpf}");
+/// Parses a byte count with an optional binary suffix, such as `512MiB` or `2G`
+fn parse_size(s: &str) -> Result<u64, String> {
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+    let multiplier: u64 = match suffix.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => return Err(String::from("must be a size such as 512MiB")),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(number) if number.is_finite() && number >= 0.0 => {
+            Ok((number * multiplier as f64) as u64)
+        }
+        _ => Err(String::from("must be a size such as 512MiB")),
+    }
+}
 
-    let mut capture = Device::lookup()?.open()?;
-    capture.filter(&bpf, true)?;
+fn list_interfaces() -> Result<()> {
+    for device in Device::list()? {
+        match device.desc {
+            Some(desc) => println!("{} ({})", device.name, desc),
+            None => println!("{}", device.name),
+        }
+        for address in device.addresses {
+            println!("    {}", address.addr);
+        }
+    }
+    Ok(())
+}
 
-    let counter = Arc::new(Mutex::new(0_u64));
-    let thread_counter = Arc::clone(&counter);
+/// CPU% above which `--color` shows it in red, which in the default `--cpu-mode core` is a
+/// share of one core
+const CPU_HIGH: f64 = 80.0;
 
-    let (sender, receiver) = mpsc::channel::<String>();
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
 
-    thread::spawn(move || {
-        while let Ok(packet) = capture.next() {
-            let mut bytes = thread_counter.lock().unwrap();
-            *bytes += packet.header.len as u64;
-            drop(bytes);
+/// Wraps `text` in the escape code for `color` if `on`
+fn highlight(text: String, color: &str, on: bool) -> String {
+    if on {
+        format!("{}{}{}", color, text, RESET)
+    } else {
+        text
+    }
+}
 
-            if let Ok(filter) = receiver.try_recv() {
-                println!("Received new filter");
-                capture.filter(&filter, true).unwrap();
-            }
+/// Exponential moving averages of the rates `--smooth` steadies
+struct Smoother {
+    alpha: f64,
+    prev: Option<Sample>,
+}
+
+impl Smoother {
+    fn new(alpha: f64) -> Smoother {
+        Smoother { alpha, prev: None }
+    }
+
+    /// A copy of `sample` with its rates averaged into the previous ones
+    fn smooth(&mut self, sample: &Sample) -> Sample {
+        let mut smoothed = sample.clone();
+        if let Some(prev) = &self.prev {
+            let alpha = self.alpha;
+            let ema = |value: f64, prev: f64| alpha * value + (1.0 - alpha) * prev;
+            smoothed.cpu = ema(sample.cpu, prev.cpu);
+            smoothed.cpu_user = ema(sample.cpu_user, prev.cpu_user);
+            smoothed.cpu_system = ema(sample.cpu_system, prev.cpu_system);
+            smoothed.read_bps = ema(sample.read_bps, prev.read_bps);
+            smoothed.write_bps = ema(sample.write_bps, prev.write_bps);
+            smoothed.rchar_bps = ema(sample.rchar_bps, prev.rchar_bps);
+            smoothed.wchar_bps = ema(sample.wchar_bps, prev.wchar_bps);
+            let ema_net = |value: Option<f64>, prev: Option<f64>| match (value, prev) {
+                (Some(value), Some(prev)) => Some(ema(value, prev)),
+                (value, _) => value,
+            };
+            smoothed.net_received_bps = ema_net(sample.net_received_bps, prev.net_received_bps);
+            smoothed.net_sent_bps = ema_net(sample.net_sent_bps, prev.net_sent_bps);
         }
-    });
+        self.prev = Some(smoothed.clone());
+        smoothed
+    }
+}
 
-    let mut prev_net_bytes = 0;
+/// Number of samples shown by `--graph`
+const GRAPH_WIDTH: usize = 20;
 
-    loop {
-        let delay_ms = 1000;
-        let delay_s = delay_ms / 1000;
-        thread::sleep(Duration::from_millis(delay_ms));
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
 
-        let stat = process.stat()?; // stat() re-fetches the data
-        let total_ticks = total_cpu_time()?;
-        let period = period(total_ticks, prev_total_ticks, cores);
-        let cpu = cpu_usage(&stat, &prev_stat, period);
+/// Renders the last `width` values as bars scaled to the largest of them
+fn sparkline(values: &VecDeque<f64>, width: usize) -> String {
+    let window = values.range(values.len().saturating_sub(width)..);
+    let max = window.clone().copied().fold(0.0, f64::max);
+    window
+        .map(|&value| {
+            let level = if max > 0.0 && value > 0.0 {
+                (value / max * (BARS.len() - 1) as f64).round() as usize
+            } else {
+                0
+            };
+            BARS[level.min(BARS.len() - 1)]
+        })
+        .collect()
+}
 
-        prev_stat = stat;
-        prev_total_ticks = total_ticks;
+/// Recent samples kept for `--graph`
+#[derive(Default)]
+struct Graph {
+    cpu: VecDeque<f64>,
+    net: VecDeque<f64>,
+}
 
-        let mem = process.statm()?.resident * page_size as u64;
+impl Graph {
+    fn record(&mut self, sample: &Sample) {
+        for (history, value) in [
+            (&mut self.cpu, sample.cpu),
+            (&mut self.net, sample.net_bps().unwrap_or(0.0)),
+        ] {
+            if history.len() == GRAPH_WIDTH {
+                history.pop_front();
+            }
+            history.push_back(value);
+        }
+    }
+}
 
-        let io = process.io()?;
-        let read_bps = (io.read_bytes - prev_bytes_read) / delay_s;
-        let write_bps = (io.write_bytes - prev_bytes_written) / delay_s;
-        let io_rate = read_bps + write_bps;
+impl fmt::Display for Graph {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "CPU {} Net {}",
+            sparkline(&self.cpu, GRAPH_WIDTH),
+            sparkline(&self.net, GRAPH_WIDTH)
+        )
+    }
+}
 
-        prev_bytes_read = io.read_bytes;
-        prev_bytes_written = io.write_bytes;
+fn print_sample(sample: &Sample, args: &Args, graph: Option<&Graph>, color: bool) {
+    let cpu = if args.cpu_breakdown {
+        format!(
+            "{:.1}% (usr {:.1}% sys {:.1}%)",
+            sample.cpu, sample.cpu_user, sample.cpu_system
+        )
+    } else {
+        format!("{:.1}%", sample.cpu)
+    };
+    let cpu = highlight(cpu, RED, color && sample.cpu > CPU_HIGH);
+    let rss = highlight(
+        format!("{}B", sample.rss),
+        YELLOW,
+        color && sample.rss > args.color_mem,
+    );
 
-        let net_bytes = *counter.lock().unwrap();
-        let byte_diff = (net_bytes - prev_net_bytes) / delay_s;
-        prev_net_bytes = net_bytes;
+    let line = format!(
+        "CPU: {} Threads: {} vctx/s: {:.0} nvctx/s: {:.0} RSS: {} VSZ: {}B Swap: {} \
+         I/O: r{:.0}B/s w{:.0}B/s Net: {} FDs: {}",
+        cpu,
+        sample.threads,
+        sample.vctx_ps,
+        sample.nvctx_ps,
+        rss,
+        sample.vsz,
+        sample
+            .swap
+            .map_or(String::from("N/A"), |swap| format!("{}B", swap)),
+        sample.read_bps,
+        sample.write_bps,
+        match (sample.net_received_bps, sample.net_sent_bps) {
+            (Some(received), Some(sent)) => format!("↓{:.0}B ↑{:.0}B", received, sent),
+            _ => String::from("N/A"),
+        },
+        sample.fd_count
+    );
+    let line = match &sample.cgroup {
+        Some(cgroup) => format!(
+            "{} Cgroup: CPU: {:.1}% Mem: {}B",
+            line, cgroup.cpu, cgroup.memory
+        ),
+        None => line,
+    };
+    let line = if args.syscall_io {
+        format!(
+            "{} Syscall I/O: r{:.0}B/s w{:.0}B/s",
+            line, sample.rchar_bps, sample.wchar_bps
+        )
+    } else {
+        line
+    };
+    match graph {
+        Some(graph) => println!("{} {}", line, graph),
+        None => println!("{}", line),
+    }
 
+    if args.per_pid {
+        for process in &sample.processes {
+            println!(
+                "  PID {}: CPU: {} Threads: {} RSS: {} VSZ: {}B I/O: r{:.0}B/s w{:.0}B/s FDs: {}",
+                process.pid,
+                highlight(
+                    format!("{:.1}%", process.cpu),
+                    RED,
+                    color && process.cpu > CPU_HIGH
+                ),
+                process.threads,
+                highlight(
+                    format!("{}B", process.rss),
+                    YELLOW,
+                    color && process.rss > args.color_mem
+                ),
+                process.vsz,
+                process.read_bps,
+                process.write_bps,
+                process.fd_count
+            );
+        }
+    }
+}
+
+fn print_connections(connections: &[Connection], mut resolver: Option<&mut Resolver>) {
+    if let Some(resolver) = &mut resolver {
+        resolver.resolve(
+            connections
+                .iter()
+                .map(|connection| connection.remote.ip())
+                .filter(|ip| !ip.is_unspecified()),
+        );
+    }
+    for connection in connections {
+        let remote = connection.remote;
+        let remote = if remote.ip().is_unspecified() {
+            String::from("*")
+        } else {
+            match resolver
+                .as_ref()
+                .and_then(|resolver| resolver.name(remote.ip()))
+            {
+                Some(name) => format!("{}:{}", name, remote.port()),
+                None => remote.to_string(),
+            }
+        };
         println!(
-            "CPU: {:.1}% Mem: {}B I/O: {}B Net: {}B",
-            cpu, mem, io_rate, byte_diff
+            "  {} {} -> {} {}",
+            connection.protocol, connection.local, remote, connection.state
         );
+    }
+}
+
+fn print_disks(sample: &Sample) {
+    for disk in &sample.disks {
+        println!(
+            "  Disk {}: r{:.0}B/s w{:.0}B/s",
+            disk.name, disk.read_bps, disk.write_bps
+        );
+    }
+}
+
+fn print_threads(sample: &Sample, count: usize) {
+    for thread in sample.per_thread.iter().take(count) {
+        println!(
+            "  Thread {} ({}) of {}: {:.1}%",
+            thread.tid, thread.name, thread.pid, thread.cpu
+        );
+    }
+}
+
+fn print_flows(sample: &Sample, count: usize) {
+    for flow in sample.flows.iter().take(count) {
+        println!(
+            "  {} :{} <-> {} ↓{:.0}B/s ↑{:.0}B/s",
+            flow.flow.protocol,
+            flow.flow.local_port,
+            flow.flow.remote,
+            flow.received_bps,
+            flow.sent_bps
+        );
+    }
+}
+
+/// Describes each `--alert-*` threshold the sample exceeds
+fn alerts(sample: &Sample, args: &Args) -> Vec<String> {
+    let mut alerts = Vec::new();
+    if let Some(limit) = args.alert_cpu.filter(|&limit| sample.cpu > limit) {
+        alerts.push(format!("CPU {:.1}% is above {}%", sample.cpu, limit));
+    }
+    if let Some(limit) = args.alert_mem.filter(|&limit| sample.rss > limit) {
+        alerts.push(format!("RSS {}B is above {}B", sample.rss, limit));
+    }
+    let net_bps = sample.net_bps().unwrap_or(0.0);
+    if let Some(limit) = args.alert_net.filter(|&limit| net_bps > limit as f64) {
+        alerts.push(format!("Net {:.0}B/s is above {}B/s", net_bps, limit));
+    }
+    alerts
+}
+
+// Running processes with any of the names, waiting for one to start with --follow
+fn find_named(names: &[String], args: &Args, shutdown: &AtomicBool) -> Result<Vec<i32>> {
+    let mut waiting = false;
+    loop {
+        let mut pids = Vec::new();
+        for name in names {
+            pids.extend(find_processes(name)?);
+        }
+        if !pids.is_empty() || !args.follow || shutdown.load(Ordering::Relaxed) {
+            return Ok(pids);
+        }
+        if !waiting {
+            println!("waiting for a process named {}", names.join(" or "));
+            waiting = true;
+        }
+        if sleep_until_shutdown(args.interval, shutdown) {
+            return Ok(Vec::new());
+        }
+    }
+}
+
+/// How often a sleep between samples checks whether Ctrl-C was pressed
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+// Sleeps for `secs` in short steps, so that a long --interval doesn't hold up Ctrl-C. Returns
+// whether it was pressed
+fn sleep_until_shutdown(secs: f64, shutdown: &AtomicBool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs_f64(secs);
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            return true;
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return false;
+        }
+        thread::sleep(left.min(SHUTDOWN_POLL));
+    }
+}
+
+/// Prints a line per sample until the duration is up, the processes exit or Ctrl-C is pressed.
+/// Returns whether any alert fired
+fn print_samples(
+    monitor: &mut Monitor,
+    args: &Args,
+    summary: &mut Summary,
+    outputs: &mut Outputs,
+) -> Result<bool> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let handler_shutdown = Arc::clone(&shutdown);
+    ctrlc::set_handler(move || handler_shutdown.store(true, Ordering::Relaxed))
+        .context("Could not install the Ctrl-C handler")?;
+
+    let start = Instant::now();
+    let mut graph = args.graph.then(Graph::default);
+    let color = args.color.enabled();
+    let mut alerted = false;
+    let mut resolver = args.resolve.then(Resolver::new);
+    let mut smoother = args.smooth.map(Smoother::new);
+    let names = if let Some(name) = &args.name {
+        vec![name.clone()]
+    } else if args.follow_name {
+        let mut names = Vec::new();
+        for pid in monitor.pids() {
+            names.push(process_name(pid)?);
+        }
+        names.sort();
+        names.dedup();
+        names
+    } else {
+        Vec::new()
+    };
+
+    while args.duration.is_none_or(|limit| summary.elapsed < limit) {
+        if sleep_until_shutdown(args.interval, &shutdown) {
+            break;
+        }
+
+        let sample = match monitor.sample() {
+            Ok(sample) => sample,
+            Err(e) if e.is::<ProcessExited>() => {
+                println!("{} after {}s", e, start.elapsed().as_secs());
+                if !(args.follow || args.follow_name) {
+                    break;
+                }
+                // A process can exit between being found and attached to, so keep looking until
+                // one is still there
+                let attached = loop {
+                    let pids = find_named(&names, args, &shutdown)?;
+                    if pids.is_empty() {
+                        break pids;
+                    }
+                    match monitor.attach(&pids) {
+                        Ok(attached) => break attached,
+                        Err(e) if e.is::<ProcessExited>() => continue,
+                        Err(e) => return Err(e),
+                    }
+                };
+                if attached.is_empty() {
+                    break;
+                }
+                for pid in &attached {
+                    println!("re-attached to pid {}", pid);
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        summary.record(&sample);
+        outputs.record(&sample)?;
+
+        for pid in &sample.exited {
+            println!(
+                "process {} exited after {}s",
+                pid,
+                start.elapsed().as_secs()
+            );
+        }
+
+        let displayed = match &mut smoother {
+            Some(smoother) => smoother.smooth(&sample),
+            None => sample.clone(),
+        };
+        if let Some(graph) = &mut graph {
+            graph.record(&displayed);
+        }
+        print_sample(&displayed, args, graph.as_ref(), color);
+        if args.disks {
+            print_disks(&sample);
+        }
+        if let Some(count) = args.threads {
+            print_threads(&sample, count);
+        }
+        if args.connections {
+            print_connections(monitor.connections(), resolver.as_mut());
+        }
+        if let Some(count) = args.top_connections {
+            print_flows(&sample, count);
+        }
+
+        if let Some(dropped) = sample.net_dropped.filter(|&dropped| dropped > 0) {
+            warn!(
+                "the capture dropped {} packets ({} so far), network stats are undercounted",
+                dropped,
+                summary.net_dropped.unwrap_or(0)
+            );
+        }
+
+        for alert in alerts(&sample, args) {
+            warn!("{}", alert);
+            alerted = true;
+        }
+        if alerted && args.alert_exit {
+            break;
+        }
+    }
+
+    Ok(alerted)
+}
+
+// Logs go to stderr, where they stay out of the samples on stdout. RUST_LOG, when it is set,
+// overrides the level -v picks
+fn init_logging(verbosity: u8) {
+    let level = match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .format_timestamp(None)
+        .format_target(false)
+        .parse_default_env()
+        .init();
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    init_logging(args.verbose);
+    if args.list_interfaces {
+        return list_interfaces();
+    }
+
+    let config = Config {
+        interface: args.interface.clone(),
+        all_interfaces: args.all_interfaces,
+        tree: args.tree,
+        system: args.system,
+        per_thread: args.threads.is_some(),
+        cpus: args.cpus.map(|cpus| cpus as usize),
+        cpu_mode: args.cpu_mode,
+        no_net: args.no_net,
+        connections: args.connections,
+        cgroup: args.cgroup.clone(),
+        disks: args.disks,
+        flows: args.top_connections.is_some(),
+        no_loopback: args.no_loopback,
+        write_pcap: args.write_pcap.clone(),
+        snaplen: args.snaplen,
+        buffer_size: args.buffer_size,
+    };
+    let pids = match (&args.name, &args.cgroup) {
+        (Some(name), _) => {
+            let pids = find_named(std::slice::from_ref(name), &args, &AtomicBool::new(false))?;
+            if pids.is_empty() {
+                bail!("No process named {} is running", name);
+            }
+            pids
+        }
+        (None, Some(path)) if args.pids.is_empty() => {
+            let pids = CgroupReader::new(path)?.procs()?;
+            if pids.is_empty() {
+                bail!("The cgroup {} has no processes", path.display());
+            }
+            pids
+        }
+        _ => args.pids.clone(),
+    };
+    let mut monitor = Monitor::new(&pids, &config)?;
+    if let Some(e) = monitor.capture_error() {
+        warn!("network stats are disabled: {:#}", e);
+    }
+    if args.system {
+        info!("monitoring the whole system");
+    }
+    for pid in monitor.pids() {
+        info!("monitoring pid {}", pid);
+    }
+    if args.print_filter {
+        println!("{}", monitor.filter());
+    } else if !args.no_net {
+        info!("capture filter: {}", monitor.filter());
+    }
+
+    let mut outputs = Outputs::default();
+    if let Some(path) = &args.output {
+        outputs.file = Some(SampleWriter::create(path, args.format)?);
+    }
+    if let Some(addr) = args.prometheus {
+        let latest = Arc::new(Mutex::new(Sample::default()));
+        prometheus::serve(addr, args.system, Arc::clone(&latest))?;
+        outputs.metrics = Some(latest);
+    }
+
+    let mut summary = Summary::default();
+    let alerted = if args.tui {
+        tui::run(
+            &mut monitor,
+            args.interval,
+            args.duration,
+            &mut summary,
+            &mut outputs,
+        )?;
+        // The alert options conflict with --tui, so nothing can have fired
+        false
+    } else {
+        print_samples(&mut monitor, &args, &mut summary, &mut outputs)?
+    };
+
+    // Stops the capture thread
+    drop(monitor);
+
+    println!("{}", summary);
+
+    if alerted && args.alert_exit {
+        bail!("an alert threshold was exceeded");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparkline_scales_to_the_window_max() {
+        assert_eq!(sparkline(&VecDeque::from([0.0, 50.0, 100.0]), 3), "▁▅█");
+        assert_eq!(sparkline(&VecDeque::from([400.0, 0.0, 1.0, 2.0]), 3), "▁▅█");
+    }
+
+    #[test]
+    fn parse_seconds_rejects_what_a_duration_cannot_hold() {
+        assert_eq!(parse_seconds("0.5"), Ok(0.5));
+        assert!(parse_seconds("0").is_err());
+        assert!(parse_seconds("inf").is_err());
+        assert!(parse_seconds("1e30").is_err());
+    }
+
+    #[test]
+    fn parse_size_suffixes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("100MiB"), Ok(100 << 20));
+        assert_eq!(parse_size("1.5GB"), Ok(3 << 29));
+        assert!(parse_size("12 parsecs").is_err());
+        assert!(parse_size("GiB").is_err());
+    }
+
+    #[test]
+    fn smoother_averages_rates_but_not_totals() {
+        let mut smoother = Smoother::new(0.5);
+        let first = smoother.smooth(&Sample {
+            cpu: 100.0,
+            rss: 10,
+            ..Sample::default()
+        });
+        assert_eq!(first.cpu, 100.0);
+
+        let second = smoother.smooth(&Sample {
+            cpu: 0.0,
+            rss: 20,
+            ..Sample::default()
+        });
+        assert_eq!(second.cpu, 50.0);
+        assert_eq!(second.rss, 20);
+    }
 
-        sender.send(build_packet_filter(process.fd()?, procfs::net::tcp()?))?;
+    #[test]
+    fn sparkline_of_idle_values() {
+        assert_eq!(sparkline(&VecDeque::from([0.0, 0.0]), 5), "▁▁");
+        assert_eq!(sparkline(&VecDeque::new(), 5), "");
     }
 }
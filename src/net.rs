@@ -0,0 +1,1031 @@
+//! Capturing the network traffic of a process's sockets
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, error, info, warn};
+use pcap::{Active, Capture, Device, Linktype, Savefile};
+use procfs::net::{TcpNetEntry, TcpState, UdpNetEntry, UdpState};
+use procfs::process::{FDInfo, FDTarget};
+
+use crate::Config;
+
+/// A TCP or UDP socket owned by a monitored process
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Connection {
+    pub protocol: &'static str,
+    pub local: SocketAddr,
+    /// The peer, or an unspecified address for a socket that isn't connected
+    pub remote: SocketAddr,
+    /// Socket state as netstat names it, e.g. `ESTABLISHED` or `LISTEN`
+    pub state: &'static str,
+}
+
+impl Connection {
+    fn new(
+        protocol: &'static str,
+        local: SocketAddr,
+        remote: SocketAddr,
+        state: &'static str,
+    ) -> Connection {
+        Connection {
+            protocol,
+            local: SocketAddr::new(wire_address(local.ip()), local.port()),
+            remote: SocketAddr::new(wire_address(remote.ip()), remote.port()),
+            state,
+        }
+    }
+
+    // Traffic that can only be with the same host, which never reaches a NIC. A socket that
+    // isn't connected counts when it's bound to loopback, since only local peers can reach it
+    fn is_loopback(&self) -> bool {
+        self.local.ip().is_loopback()
+            && (self.remote.ip().is_loopback() || self.remote.ip().is_unspecified())
+    }
+}
+
+fn tcp_state(state: &TcpState) -> &'static str {
+    match state {
+        TcpState::Established => "ESTABLISHED",
+        TcpState::SynSent => "SYN_SENT",
+        TcpState::SynRecv => "SYN_RECV",
+        TcpState::FinWait1 => "FIN_WAIT1",
+        TcpState::FinWait2 => "FIN_WAIT2",
+        TcpState::TimeWait => "TIME_WAIT",
+        TcpState::Close => "CLOSE",
+        TcpState::CloseWait => "CLOSE_WAIT",
+        TcpState::LastAck => "LAST_ACK",
+        TcpState::Listen => "LISTEN",
+        TcpState::Closing => "CLOSING",
+        TcpState::NewSynRecv => "NEW_SYN_RECV",
+    }
+}
+
+// UDP has no real states, only whether the socket is connected to a peer
+fn udp_state(state: &UdpState) -> &'static str {
+    match state {
+        UdpState::Established => "ESTABLISHED",
+        UdpState::Close => "UNCONN",
+    }
+}
+
+// An IPv6 socket talking to an IPv4 peer shows up in tcp6/udp6 with IPv4-mapped addresses
+// (::ffff:a.b.c.d), but its packets are plain IPv4 on the wire
+fn wire_address(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+// Find the entries of the TCP and UDP tables that correspond to a socket in the fd list
+pub(crate) fn connections<F, T, U>(fd: F, tcp: T, udp: U) -> Vec<Connection>
+where
+    F: IntoIterator<Item = FDInfo>,
+    T: IntoIterator<Item = TcpNetEntry>,
+    U: IntoIterator<Item = UdpNetEntry>,
+{
+    // Given a list of file descriptors, find the inodes of those that are sockets
+    let inodes: HashSet<_> = fd
+        .into_iter()
+        .filter_map(|fd| match fd.target {
+            FDTarget::Socket(inode) => Some(inode),
+            _ => None,
+        })
+        .collect();
+
+    let tcp = tcp
+        .into_iter()
+        .filter(|entry| inodes.contains(&entry.inode))
+        .map(|entry| {
+            Connection::new(
+                "tcp",
+                entry.local_address,
+                entry.remote_address,
+                tcp_state(&entry.state),
+            )
+        });
+    let udp = udp
+        .into_iter()
+        .filter(|entry| inodes.contains(&entry.inode))
+        .map(|entry| {
+            Connection::new(
+                "udp",
+                entry.local_address,
+                entry.remote_address,
+                udp_state(&entry.state),
+            )
+        });
+
+    tcp.chain(udp).collect()
+}
+
+// The connections of the sockets among the given fds (which may come from several processes)
+// Sorted, so that two calls can be compared to tell whether the sockets changed
+pub(crate) fn process_connections(fds: Vec<FDInfo>) -> Result<Vec<Connection>> {
+    let tcp = procfs::net::tcp()?.into_iter().chain(procfs::net::tcp6()?);
+    let udp = procfs::net::udp()?.into_iter().chain(procfs::net::udp6()?);
+    let mut connections = connections(fds, tcp, udp);
+    connections.sort();
+    Ok(connections)
+}
+
+// Create a Berkley Packet Filter to find packets belonging to one of the ports in use by the process
+// Packets are considered a match if they have the same protocol, host address, and destination address
+// Therefore, we create a filter like:
+// (tcp and host 127.0.0.1 and host 127.0.0.1 and port 33791 and port 60914) or (udp and ...)
+// A process without any sockets gets an empty filter, which Filter::installed_program
+// turns into one that matches nothing
+pub(crate) fn build_packet_filter(connections: &[Connection]) -> String {
+    connections
+        .iter()
+        .map(connection_filter)
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
+// A socket that isn't connected (e.g. a UDP server) has no remote address, and one bound to
+// all interfaces has no specific local address, so those parts are left out of its clause
+fn connection_filter(connection: &Connection) -> String {
+    let mut terms = vec![connection.protocol.to_string()];
+    for addr in [connection.local, connection.remote] {
+        if !addr.ip().is_unspecified() {
+            // IpAddr's Display gives a bare address (no brackets or port), which is what BPF expects
+            terms.push(format!("host {}", addr.ip()));
+        }
+    }
+    for addr in [connection.local, connection.remote] {
+        if addr.port() != 0 {
+            terms.push(format!("port {}", addr.port()));
+        }
+    }
+    format!("({})", terms.join(" and "))
+}
+
+/// The BPF program selecting a process's packets, along with the local sockets that identify
+/// which of those packets it sent
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Filter {
+    pub program: String,
+    /// Port 0 stands for any port, and an unspecified address for any local address
+    local_sockets: Vec<SocketAddr>,
+}
+
+impl Filter {
+    /// With `skip_loopback`, traffic between local sockets isn't selected
+    pub fn new(connections: &[Connection], skip_loopback: bool) -> Filter {
+        let selected: Vec<_> = connections
+            .iter()
+            .filter(|connection| !(skip_loopback && connection.is_loopback()))
+            .copied()
+            .collect();
+        Filter {
+            program: build_packet_filter(&selected),
+            local_sockets: connections.iter().map(|c| c.local).collect(),
+        }
+    }
+
+    /// Selects all IP traffic, for measuring the whole machine. Packets from any of its
+    /// addresses count as sent, and those between two of them as sent and received
+    pub fn everything(skip_loopback: bool) -> Result<Filter> {
+        let program = if skip_loopback {
+            "(ip or ip6) and not net 127.0.0.0/8 and not host ::1"
+        } else {
+            "ip or ip6"
+        };
+        Ok(Filter {
+            program: String::from(program),
+            local_sockets: Device::list()
+                .map_err(|e| CaptureUnavailable::new("Could not list the capture devices", e))?
+                .into_iter()
+                .flat_map(|device| device.addresses)
+                .map(|address| SocketAddr::new(address.addr, 0))
+                .collect(),
+        })
+    }
+
+    fn direction(&self, packet: &Packet) -> Direction {
+        let is_local = |addr: SocketAddr| {
+            self.local_sockets.iter().any(|socket| {
+                (socket.port() == 0 || socket.port() == addr.port())
+                    && (socket.ip().is_unspecified() || wire_address(socket.ip()) == addr.ip())
+            })
+        };
+        match (is_local(packet.source), is_local(packet.destination)) {
+            (true, true) => Direction::Both,
+            (true, false) => Direction::Sent,
+            _ => Direction::Received,
+        }
+    }
+
+    // pcap treats an empty program as "match everything", which would count unrelated traffic
+    // against a process that has no sockets. "less 0" only matches packets of zero length,
+    // so nothing is counted until the process opens a socket
+    fn installed_program(&self) -> &str {
+        if self.program.is_empty() {
+            "less 0"
+        } else {
+            &self.program
+        }
+    }
+}
+
+/// The local port and remote end of a connection, which together tell its packets apart
+/// from those of the process's other connections
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Flow {
+    pub protocol: &'static str,
+    pub local_port: u16,
+    pub remote: SocketAddr,
+}
+
+/// Which way a packet went, as seen from the monitored sockets. A packet between two of
+/// them was both sent and received
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    Received,
+    Sent,
+    Both,
+}
+
+/// Bytes captured in each direction
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Bytes {
+    pub received: u64,
+    pub sent: u64,
+}
+
+impl Bytes {
+    fn add(&mut self, direction: Direction, len: u64) {
+        if direction != Direction::Received {
+            self.sent += len;
+        }
+        if direction != Direction::Sent {
+            self.received += len;
+        }
+    }
+}
+
+/// Bytes captured in total, and on each flow
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Traffic {
+    pub total: Bytes,
+    /// Only since the previous sample, unlike the totals, so that flows which went quiet are
+    /// dropped rather than kept and copied around for as long as the capture runs
+    pub flows: HashMap<Flow, Bytes>,
+    /// Packets the kernel dropped because the capture buffer was full, on all devices
+    pub dropped: u64,
+}
+
+impl Traffic {
+    fn merge(&mut self, other: &Traffic) {
+        self.total.received += other.total.received;
+        self.total.sent += other.total.sent;
+        for (flow, bytes) in &other.flows {
+            let merged = self.flows.entry(*flow).or_default();
+            merged.received += bytes.received;
+            merged.sent += bytes.sent;
+        }
+        self.dropped += other.dropped;
+    }
+}
+
+/// What one capture thread has counted, shared with the monitor
+#[derive(Default)]
+struct Counted {
+    /// Every packet read so far
+    traffic: Traffic,
+    /// The time of a sample that the monitor is waiting on
+    boundary: Option<SystemTime>,
+    /// The totals as of `boundary`, once the thread has read every packet stamped before it
+    at_boundary: Option<Traffic>,
+    /// Set when the thread has finished, so nothing should wait on it
+    stopped: bool,
+    /// Whether to count each flow as well as the totals
+    track_flows: bool,
+}
+
+impl Counted {
+    // The traffic so far, handing over the flows counted since the last time
+    fn take_traffic(&mut self) -> Traffic {
+        let flows = std::mem::take(&mut self.traffic.flows);
+        Traffic {
+            flows,
+            ..self.traffic.clone()
+        }
+    }
+
+    // Packets are read in the order they were stamped, so once one from `time` is read
+    // (or the device is idle at `time`), everything before a boundary at or before it is in
+    fn reached(&mut self, time: SystemTime) -> bool {
+        match self.boundary {
+            Some(boundary) if time >= boundary => {
+                self.at_boundary = Some(self.take_traffic());
+                self.boundary = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+type SharedCount = Arc<(Mutex<Counted>, Condvar)>;
+
+/// Bytes captured of each packet by default, enough for the link, IP and TCP/UDP port headers
+/// (even with IPv4 options or a VLAN tag) since only those and the packet length are read
+const DEFAULT_SNAPLEN: i32 = 96;
+
+/// How long a sample waits for a capture thread that has fallen behind to reach it, after which
+/// the packets it hasn't read yet are left for the next sample
+const BOUNDARY_WAIT: Duration = Duration::from_millis(250);
+
+/// How often each capture thread checks pcap's statistics for dropped packets
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counts the bytes of packets matching a BPF filter, with a background thread per capture device
+pub(crate) struct NetCapture {
+    counts: Vec<SharedCount>,
+    filters: Vec<mpsc::Sender<Filter>>,
+    shutdown: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// Capturing couldn't start for want of privileges or a working pcap, rather than because of
+/// how it was configured
+#[derive(Debug)]
+pub(crate) struct CaptureUnavailable {
+    message: String,
+    source: pcap::Error,
+}
+
+impl CaptureUnavailable {
+    fn new(message: impl Into<String>, source: pcap::Error) -> CaptureUnavailable {
+        CaptureUnavailable {
+            message: message.into(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for CaptureUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for CaptureUnavailable {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl NetCapture {
+    /// Fails with [`CaptureUnavailable`] when no capture device could be opened
+    pub fn start(filter: &Filter, config: &Config) -> Result<NetCapture> {
+        // A savefile has a single link type, so it can only hold one device's packets
+        if config.all_interfaces && config.write_pcap.is_some() {
+            bail!("A pcap file can only be written when capturing on a single device");
+        }
+
+        let captures = if config.all_interfaces {
+            // "any" would count every packet a second time, and devices that can't be opened
+            // (or don't carry IP traffic, so the filter doesn't compile) are skipped
+            let mut captures = Vec::new();
+            let mut first_error = None;
+            let devices = Device::list()
+                .map_err(|e| CaptureUnavailable::new("Could not list the capture devices", e))?;
+            for device in devices.into_iter().filter(|device| device.name != "any") {
+                let name = device.name.clone();
+                match open_capture(device, filter, config) {
+                    Ok(capture) => captures.push(capture),
+                    Err(e) => {
+                        info!("skipping capture device {}: {:#}", name, e);
+                        first_error.get_or_insert(e);
+                    }
+                }
+            }
+            if captures.is_empty() {
+                // Most likely every device failed for the same reason, such as privileges
+                return Err(first_error.unwrap_or_else(|| anyhow!("No capture devices found")));
+            }
+            captures
+        } else {
+            vec![open_capture(
+                capture_device(config.interface.as_deref())?,
+                filter,
+                config,
+            )?]
+        };
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let mut counts = Vec::new();
+        let mut filters = Vec::new();
+        let mut threads = Vec::new();
+
+        for (device, capture) in captures {
+            let savefile =
+                match &config.write_pcap {
+                    Some(path) => Some(capture.savefile(path).with_context(|| {
+                        format!("Could not create pcap file {}", path.display())
+                    })?),
+                    None => None,
+                };
+            let (sender, receiver) = mpsc::channel::<Filter>();
+            let filter = filter.clone();
+            let count = SharedCount::new((
+                Mutex::new(Counted {
+                    track_flows: config.flows,
+                    ..Counted::default()
+                }),
+                Condvar::new(),
+            ));
+            let thread_count = Arc::clone(&count);
+            let shutdown = Arc::clone(&shutdown);
+            counts.push(count);
+            filters.push(sender);
+            threads.push(thread::spawn(move || {
+                count_packets(
+                    &device,
+                    capture,
+                    savefile,
+                    filter,
+                    receiver,
+                    &thread_count,
+                    &shutdown,
+                );
+                let (counted, condvar) = &*thread_count;
+                counted.lock().unwrap().stopped = true;
+                condvar.notify_all();
+            }));
+        }
+
+        Ok(NetCapture {
+            counts,
+            filters,
+            shutdown,
+            threads,
+        })
+    }
+
+    /// Total bytes captured in packets stamped before `boundary`, so that a burst just before
+    /// a sample counts towards it even if the capture threads only read it afterwards
+    pub fn traffic_at(&self, boundary: SystemTime) -> Traffic {
+        for count in &self.counts {
+            let mut counted = count.0.lock().unwrap();
+            counted.boundary = Some(boundary);
+            counted.at_boundary = None;
+        }
+
+        let mut traffic = Traffic::default();
+        for count in &self.counts {
+            let (counted, condvar) = &**count;
+            let (mut counted, _) = condvar
+                .wait_timeout_while(counted.lock().unwrap(), BOUNDARY_WAIT, |counted| {
+                    counted.at_boundary.is_none() && !counted.stopped
+                })
+                .unwrap();
+            counted.boundary = None;
+            match counted.at_boundary.take() {
+                Some(at_boundary) => traffic.merge(&at_boundary),
+                None => traffic.merge(&counted.take_traffic()),
+            }
+        }
+        traffic
+    }
+
+    /// Replaces the filter on every capture device
+    pub fn set_filter(&self, filter: Filter) -> Result<()> {
+        for sender in &self.filters {
+            sender
+                .send(filter.clone())
+                .context("The capture thread stopped unexpectedly")?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NetCapture {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            thread.join().ok();
+        }
+    }
+}
+
+// Opens a capture on the device, returned along with the device's name
+fn open_capture(
+    device: Device,
+    filter: &Filter,
+    config: &Config,
+) -> Result<(String, Capture<Active>)> {
+    let name = device.name.clone();
+    // The read timeout lets the capture thread notice a shutdown even when no packets arrive
+    let unavailable =
+        |e| CaptureUnavailable::new(format!("Could not open capture device {}", name), e);
+    let mut capture = Capture::from_device(device)
+        .map_err(unavailable)?
+        .timeout(100);
+    // Packets saved to a file are kept whole unless asked otherwise
+    match (config.snaplen, &config.write_pcap) {
+        (Some(snaplen), _) => capture = capture.snaplen(snaplen),
+        (None, None) => capture = capture.snaplen(DEFAULT_SNAPLEN),
+        (None, Some(_)) => {}
+    }
+    if let Some(size) = config.buffer_size {
+        let size: i32 = size
+            .try_into()
+            .context("The capture buffer size is too large")?;
+        capture = capture.buffer_size(size);
+    }
+    let mut capture = capture.open().map_err(unavailable)?;
+    capture.filter(filter.installed_program(), true)?;
+    info!("capturing on {}", name);
+    Ok((name, capture))
+}
+
+// Runs on a capture thread until shutdown, adding each matching packet to the traffic totals
+// (and the savefile, which is closed properly when it's dropped on the way out)
+fn count_packets(
+    device: &str,
+    mut capture: Capture<Active>,
+    mut savefile: Option<Savefile>,
+    mut filter: Filter,
+    filters: mpsc::Receiver<Filter>,
+    count: &SharedCount,
+    shutdown: &AtomicBool,
+) {
+    let (counted, condvar) = &**count;
+    let linktype = capture.get_datalink();
+    let mut dropped = 0;
+    let mut next_stats = Instant::now() + STATS_INTERVAL;
+
+    while !shutdown.load(Ordering::Relaxed) {
+        // Checked before every read, so that a new filter is installed within one read timeout
+        // even on a quiet connection
+        if let Some(new_filter) = latest_filter(&filters) {
+            debug!(
+                "{}: installing filter {}",
+                device,
+                new_filter.installed_program()
+            );
+            match capture.filter(new_filter.installed_program(), true) {
+                Ok(()) => filter = new_filter,
+                // The previous filter keeps counting the connections it already had
+                Err(e) => error!("{}: could not install the new filter: {}", device, e),
+            }
+        }
+
+        // Dropped packets were never counted, so the totals are short by an unknown amount
+        if Instant::now() >= next_stats {
+            next_stats += STATS_INTERVAL;
+            match capture.stats() {
+                Ok(stats) => {
+                    debug!(
+                        "{}: {} packets received, {} dropped, {} dropped by the interface",
+                        device, stats.received, stats.dropped, stats.if_dropped
+                    );
+                    // The kernel's count is 32 bits and wraps on a busy enough link
+                    let new_drops = stats.dropped.wrapping_sub(dropped);
+                    dropped = stats.dropped;
+                    if new_drops > 0 {
+                        counted.lock().unwrap().traffic.dropped += u64::from(new_drops);
+                    }
+                }
+                Err(e) => debug!("{}: could not read the capture statistics: {}", device, e),
+            }
+        }
+
+        let packet = match capture.next() {
+            Ok(packet) => packet,
+            Err(pcap::Error::TimeoutExpired) => {
+                // Nothing was waiting to be read, so the thread has caught up to now
+                if counted.lock().unwrap().reached(SystemTime::now()) {
+                    condvar.notify_all();
+                }
+                continue;
+            }
+            Err(e) => {
+                warn!("{}: capturing stopped: {}", device, e);
+                break;
+            }
+        };
+        if let Some(savefile) = &mut savefile {
+            savefile.write(&packet);
+        }
+
+        let parsed = parse_packet(linktype, packet.data);
+        let direction = parsed
+            .as_ref()
+            .map_or(Direction::Received, |parsed| filter.direction(parsed));
+        let len = packet.header.len as u64;
+        let mut counted = counted.lock().unwrap();
+        if counted.reached(packet_time(packet.header)) {
+            condvar.notify_all();
+        }
+        counted.traffic.total.add(direction, len);
+        let flow = parsed
+            .filter(|_| counted.track_flows)
+            .and_then(|parsed| parsed.flow(direction != Direction::Received));
+        if let Some(flow) = flow {
+            counted
+                .traffic
+                .flows
+                .entry(flow)
+                .or_default()
+                .add(direction, len);
+        }
+        drop(counted);
+    }
+}
+
+// When the kernel stamped a packet, which pcap gives to the microsecond
+fn packet_time(header: &pcap::PacketHeader) -> SystemTime {
+    UNIX_EPOCH
+        + Duration::from_secs(header.ts.tv_sec as u64)
+        + Duration::from_micros(header.ts.tv_usec as u64)
+}
+
+// Drains the channel, since only the most recently sent filter matters
+fn latest_filter(filters: &mpsc::Receiver<Filter>) -> Option<Filter> {
+    filters.try_iter().last()
+}
+
+// Find the capture device with the given name, or pcap's default one
+fn capture_device(interface: Option<&str>) -> Result<Device> {
+    let name = match interface {
+        Some(name) => name,
+        None => {
+            return Device::lookup()
+                .map_err(|e| CaptureUnavailable::new("Could not find a capture device", e).into())
+        }
+    };
+
+    let devices = Device::list()?;
+    match devices.iter().find(|device| device.name == name) {
+        Some(device) => Ok(device.clone()),
+        None => {
+            let names: Vec<_> = devices.iter().map(|device| device.name.as_str()).collect();
+            bail!(
+                "No capture device named {}. Available devices: {}",
+                name,
+                names.join(", ")
+            )
+        }
+    }
+}
+
+// Offset of the IP header within a captured frame, for the link types pcap gives us on Linux
+fn network_offset(linktype: Linktype, data: &[u8]) -> Option<usize> {
+    match linktype.0 {
+        // BSD loopback
+        0 => Some(4),
+        // Ethernet, skipping over any 802.1Q/802.1ad VLAN tags
+        1 => {
+            let mut offset = 12;
+            while let [0x81, 0x00] | [0x88, 0xa8] = data.get(offset..offset + 2)? {
+                offset += 4;
+            }
+            Some(offset + 2)
+        }
+        // Raw IP
+        12 | 14 | 101 => Some(0),
+        // Linux "cooked" capture, v1 and v2
+        113 => Some(16),
+        276 => Some(20),
+        _ => None,
+    }
+}
+
+/// The endpoints of a captured IP packet. Ports are 0 for anything but TCP and UDP
+struct Packet {
+    protocol: Option<&'static str>,
+    source: SocketAddr,
+    destination: SocketAddr,
+}
+
+impl Packet {
+    fn flow(&self, sent: bool) -> Option<Flow> {
+        let (local, remote) = if sent {
+            (self.source, self.destination)
+        } else {
+            (self.destination, self.source)
+        };
+        Some(Flow {
+            protocol: self.protocol?,
+            local_port: local.port(),
+            remote,
+        })
+    }
+}
+
+fn parse_packet(linktype: Linktype, data: &[u8]) -> Option<Packet> {
+    let ip = data.get(network_offset(linktype, data)?..)?;
+    let (protocol, source, destination, transport) = match ip.first()? >> 4 {
+        4 => {
+            let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            let header_len = usize::from(ip[0] & 0x0f) * 4;
+            // Only the first fragment of a packet carries the TCP/UDP header
+            let fragment_offset = u16::from_be_bytes([ip.get(6)? & 0x1f, *ip.get(7)?]);
+            let transport = if fragment_offset == 0 {
+                ip.get(header_len..)
+            } else {
+                None
+            };
+            (*ip.get(9)?, IpAddr::from(src), IpAddr::from(dst), transport)
+        }
+        // Extension headers aren't followed, so such packets count without ports
+        6 => {
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            (
+                *ip.get(6)?,
+                IpAddr::from(src),
+                IpAddr::from(dst),
+                ip.get(40..),
+            )
+        }
+        _ => return None,
+    };
+
+    let protocol = match protocol {
+        6 => Some("tcp"),
+        17 => Some("udp"),
+        _ => None,
+    };
+    // TCP and UDP both start with the source and destination ports
+    let ports = protocol.and(transport).and_then(|transport| {
+        let ports: [u8; 4] = transport.get(0..4)?.try_into().ok()?;
+        Some((
+            u16::from_be_bytes([ports[0], ports[1]]),
+            u16::from_be_bytes([ports[2], ports[3]]),
+        ))
+    });
+    let (source_port, destination_port) = ports.unwrap_or((0, 0));
+
+    Some(Packet {
+        protocol,
+        source: SocketAddr::new(source, source_port),
+        destination: SocketAddr::new(destination, destination_port),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use procfs::net::{TcpState, UdpState};
+
+    fn socket(fd: u32, inode: u64) -> FDInfo {
+        FDInfo {
+            fd,
+            mode: 0o700,
+            target: FDTarget::Socket(inode),
+        }
+    }
+
+    fn tcp(local: &str, remote: &str, inode: u64) -> TcpNetEntry {
+        TcpNetEntry {
+            local_address: local.parse().unwrap(),
+            remote_address: remote.parse().unwrap(),
+            state: TcpState::Established,
+            rx_queue: 0,
+            tx_queue: 0,
+            inode,
+        }
+    }
+
+    fn udp(local: &str, remote: &str, inode: u64) -> UdpNetEntry {
+        UdpNetEntry {
+            local_address: local.parse().unwrap(),
+            remote_address: remote.parse().unwrap(),
+            state: UdpState::Established,
+            rx_queue: 0,
+            tx_queue: 0,
+            inode,
+        }
+    }
+
+    #[test]
+    fn filter_includes_tcp_and_udp_sockets() {
+        let fds = vec![socket(3, 100), socket(4, 200)];
+        let tcp = vec![
+            tcp("10.0.0.2:33791", "10.0.0.1:443", 100),
+            tcp("10.0.0.2:40000", "10.0.0.1:80", 999),
+        ];
+        let udp = vec![
+            udp("10.0.0.2:5353", "10.0.0.53:53", 200),
+            udp("0.0.0.0:9999", "0.0.0.0:0", 998),
+        ];
+
+        assert_eq!(
+            build_packet_filter(&connections(fds, tcp, udp)),
+            "(tcp and host 10.0.0.2 and host 10.0.0.1 and port 33791 and port 443) or \
+             (udp and host 10.0.0.2 and host 10.0.0.53 and port 5353 and port 53)"
+        );
+    }
+
+    #[test]
+    fn filter_omits_unspecified_addresses() {
+        let fds = vec![socket(3, 100)];
+        let udp = vec![udp("0.0.0.0:5353", "0.0.0.0:0", 100)];
+
+        assert_eq!(
+            build_packet_filter(&connections(fds, vec![], udp)),
+            "(udp and port 5353)"
+        );
+    }
+
+    #[test]
+    fn filter_is_empty_without_matching_sockets() {
+        let fds = vec![socket(3, 100)];
+        let tcp = vec![tcp("10.0.0.2:33791", "10.0.0.1:443", 999)];
+        let udp = vec![udp("10.0.0.2:5353", "10.0.0.53:53", 998)];
+
+        let filter = Filter::new(&connections(fds, tcp, udp), false);
+        assert_eq!(filter.program, "");
+        assert_eq!(filter.installed_program(), "less 0");
+    }
+
+    #[test]
+    fn filter_includes_ipv6_sockets() {
+        let fds = vec![socket(3, 100)];
+        let tcp = vec![tcp("[2001:db8::2]:33791", "[2001:db8::1]:443", 100)];
+
+        assert_eq!(
+            build_packet_filter(&connections(fds, tcp, vec![])),
+            "(tcp and host 2001:db8::2 and host 2001:db8::1 and port 33791 and port 443)"
+        );
+    }
+
+    #[test]
+    fn filter_uses_ipv4_for_mapped_addresses() {
+        let fds = vec![socket(3, 100)];
+        let tcp = vec![tcp("[::ffff:10.0.0.2]:33791", "[::ffff:10.0.0.1]:443", 100)];
+
+        assert_eq!(
+            build_packet_filter(&connections(fds, tcp, vec![])),
+            "(tcp and host 10.0.0.2 and host 10.0.0.1 and port 33791 and port 443)"
+        );
+    }
+
+    #[test]
+    fn latest_filter_wins() {
+        let (sender, receiver) = mpsc::channel();
+        assert!(latest_filter(&receiver).is_none());
+
+        for program in ["(tcp and port 1)", "(tcp and port 2)", "(tcp and port 3)"] {
+            let filter = Filter {
+                program: program.to_string(),
+                ..Filter::default()
+            };
+            sender.send(filter).unwrap();
+        }
+
+        assert_eq!(
+            latest_filter(&receiver).unwrap().program,
+            "(tcp and port 3)"
+        );
+        assert!(latest_filter(&receiver).is_none());
+    }
+
+    #[test]
+    fn filter_skips_loopback_connections() {
+        let fds = vec![socket(3, 100), socket(4, 200), socket(5, 300)];
+        let tcp = vec![
+            tcp("127.0.0.1:33791", "127.0.0.1:5432", 100),
+            tcp("127.0.0.1:8080", "0.0.0.0:0", 200),
+            tcp("[::1]:40000", "[::1]:6379", 300),
+        ];
+        let connections = connections(fds, tcp, vec![]);
+
+        let filter = Filter::new(&connections, true);
+        assert_eq!(filter.program, "");
+        assert_eq!(filter.installed_program(), "less 0");
+        assert_ne!(Filter::new(&connections, false).program, "");
+    }
+
+    #[test]
+    fn filter_keeps_remote_connections_when_skipping_loopback() {
+        let fds = vec![socket(3, 100), socket(4, 200)];
+        let tcp = vec![
+            tcp("127.0.0.1:33791", "127.0.0.1:5432", 100),
+            tcp("10.0.0.2:40000", "10.0.0.1:443", 200),
+        ];
+
+        assert_eq!(
+            Filter::new(&connections(fds, tcp, vec![]), true).program,
+            "(tcp and host 10.0.0.2 and host 10.0.0.1 and port 40000 and port 443)"
+        );
+    }
+
+    // A TCP segment over raw IP, from 10.0.0.2:33791 to 10.0.0.1:443
+    fn tcp_packet() -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 40, 0, 0, 0x40, 0, 64, 6, 0, 0];
+        packet.extend([10, 0, 0, 2, 10, 0, 0, 1]);
+        packet.extend(33791u16.to_be_bytes());
+        packet.extend(443u16.to_be_bytes());
+        packet.extend([0; 16]);
+        packet
+    }
+
+    #[test]
+    fn parse_packet_reads_the_tcp_endpoints() {
+        let packet = parse_packet(Linktype(12), &tcp_packet()).unwrap();
+        assert_eq!(packet.protocol, Some("tcp"));
+        assert_eq!(packet.source, "10.0.0.2:33791".parse().unwrap());
+        assert_eq!(packet.destination, "10.0.0.1:443".parse().unwrap());
+    }
+
+    #[test]
+    fn flow_is_keyed_by_the_local_port_in_either_direction() {
+        let packet = parse_packet(Linktype(12), &tcp_packet()).unwrap();
+        let expected = Flow {
+            protocol: "tcp",
+            local_port: 33791,
+            remote: "10.0.0.1:443".parse().unwrap(),
+        };
+        assert_eq!(packet.flow(true), Some(expected));
+
+        let reply = Packet {
+            protocol: packet.protocol,
+            source: packet.destination,
+            destination: packet.source,
+        };
+        assert_eq!(reply.flow(false), Some(expected));
+    }
+
+    fn packet(source: &str, destination: &str) -> Packet {
+        Packet {
+            protocol: Some("tcp"),
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn direction_tells_the_ends_of_a_loopback_connection_apart() {
+        let fds = vec![socket(3, 100)];
+        let tcp = vec![tcp("127.0.0.1:40000", "127.0.0.1:5432", 100)];
+        let filter = Filter::new(&connections(fds, tcp, vec![]), false);
+
+        let request = packet("127.0.0.1:40000", "127.0.0.1:5432");
+        assert_eq!(filter.direction(&request), Direction::Sent);
+        let reply = packet("127.0.0.1:5432", "127.0.0.1:40000");
+        assert_eq!(filter.direction(&reply), Direction::Received);
+    }
+
+    #[test]
+    fn direction_matches_any_address_of_an_unspecified_socket() {
+        let fds = vec![socket(3, 100)];
+        let udp = vec![udp("0.0.0.0:53", "0.0.0.0:0", 100)];
+        let filter = Filter::new(&connections(fds, vec![], udp), false);
+
+        let answer = packet("10.0.0.5:53", "10.0.0.9:41000");
+        assert_eq!(filter.direction(&answer), Direction::Sent);
+        let query = packet("10.0.0.9:41000", "10.0.0.5:53");
+        assert_eq!(filter.direction(&query), Direction::Received);
+    }
+
+    #[test]
+    fn counted_keeps_the_totals_from_before_the_boundary() {
+        let boundary = UNIX_EPOCH + Duration::from_secs(100);
+        let mut counted = Counted {
+            boundary: Some(boundary),
+            ..Counted::default()
+        };
+        counted.traffic.total.add(Direction::Received, 500);
+        assert!(!counted.reached(boundary - Duration::from_millis(1)));
+        assert!(counted.reached(boundary));
+        counted.traffic.total.add(Direction::Received, 700);
+
+        assert_eq!(counted.at_boundary.as_ref().unwrap().total.received, 500);
+        assert!(!counted.reached(boundary + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn flows_are_only_kept_until_a_sample_takes_them() {
+        let flow = packet("10.0.0.2:33791", "10.0.0.1:443").flow(true).unwrap();
+        let mut counted = Counted::default();
+        counted.traffic.total.add(Direction::Sent, 100);
+        counted
+            .traffic
+            .flows
+            .entry(flow)
+            .or_default()
+            .add(Direction::Sent, 100);
+
+        assert_eq!(counted.take_traffic().flows[&flow].sent, 100);
+        let quiet = counted.take_traffic();
+        assert!(quiet.flows.is_empty());
+        assert_eq!(quiet.total.sent, 100);
+    }
+}
@@ -0,0 +1,161 @@
+//! Writing samples to a file for later analysis
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use usg::Sample;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// One JSON object per line
+    Json,
+    Csv,
+}
+
+/// The columns written for each sample, with `None` for values that aren't known
+fn fields(sample: &Sample) -> Vec<(&'static str, Option<String>)> {
+    vec![
+        ("elapsed", Some(sample.elapsed.to_string())),
+        ("cpu", Some(sample.cpu.to_string())),
+        ("cpu_user", Some(sample.cpu_user.to_string())),
+        ("cpu_system", Some(sample.cpu_system.to_string())),
+        ("rss", Some(sample.rss.to_string())),
+        ("vsz", Some(sample.vsz.to_string())),
+        ("swap", sample.swap.map(|swap| swap.to_string())),
+        ("bytes_read", Some(sample.bytes_read.to_string())),
+        ("bytes_written", Some(sample.bytes_written.to_string())),
+        ("read_bps", Some(sample.read_bps.to_string())),
+        ("write_bps", Some(sample.write_bps.to_string())),
+        ("rchar_bps", Some(sample.rchar_bps.to_string())),
+        ("wchar_bps", Some(sample.wchar_bps.to_string())),
+        (
+            "net_received",
+            sample.net_received.map(|value| value.to_string()),
+        ),
+        ("net_sent", sample.net_sent.map(|value| value.to_string())),
+        (
+            "net_received_bps",
+            sample.net_received_bps.map(|value| value.to_string()),
+        ),
+        (
+            "net_sent_bps",
+            sample.net_sent_bps.map(|value| value.to_string()),
+        ),
+        (
+            "net_dropped",
+            sample.net_dropped.map(|value| value.to_string()),
+        ),
+        ("threads", Some(sample.threads.to_string())),
+        ("vctx_ps", Some(sample.vctx_ps.to_string())),
+        ("nvctx_ps", Some(sample.nvctx_ps.to_string())),
+        ("fd_count", Some(sample.fd_count.to_string())),
+        (
+            "cgroup_cpu",
+            sample.cgroup.as_ref().map(|cgroup| cgroup.cpu.to_string()),
+        ),
+        (
+            "cgroup_memory",
+            sample
+                .cgroup
+                .as_ref()
+                .map(|cgroup| cgroup.memory.to_string()),
+        ),
+    ]
+}
+
+fn json_line(sample: &Sample) -> String {
+    let members: Vec<String> = fields(sample)
+        .into_iter()
+        .map(|(name, value)| {
+            format!(
+                "\"{}\":{}",
+                name,
+                value.unwrap_or_else(|| String::from("null"))
+            )
+        })
+        .collect();
+    format!("{{{}}}", members.join(","))
+}
+
+/// A file that samples are appended to as they're taken, flushed after each so it can be
+/// followed with `tail -f`
+pub enum SampleWriter {
+    Json(BufWriter<File>),
+    // csv::Writer does its own buffering
+    Csv(Box<csv::Writer<File>>),
+}
+
+impl SampleWriter {
+    pub fn create(path: &Path, format: Format) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Could not open output file {}", path.display()))?;
+        Ok(match format {
+            Format::Json => SampleWriter::Json(BufWriter::new(file)),
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(file);
+                writer.write_record(fields(&Sample::default()).iter().map(|(name, _)| name))?;
+                SampleWriter::Csv(Box::new(writer))
+            }
+        })
+    }
+
+    pub fn write(&mut self, sample: &Sample) -> Result<()> {
+        match self {
+            SampleWriter::Json(writer) => {
+                writeln!(writer, "{}", json_line(sample))?;
+                writer.flush()?;
+            }
+            SampleWriter::Csv(writer) => {
+                writer.write_record(
+                    fields(sample)
+                        .into_iter()
+                        .map(|(_, value)| value.unwrap_or_default()),
+                )?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Everywhere samples go besides the terminal
+#[derive(Default)]
+pub struct Outputs {
+    pub file: Option<SampleWriter>,
+    /// The latest sample, served by `--prometheus`
+    pub metrics: Option<Arc<Mutex<Sample>>>,
+}
+
+impl Outputs {
+    pub fn record(&mut self, sample: &Sample) -> Result<()> {
+        if let Some(file) = &mut self.file {
+            file.write(sample)?;
+        }
+        if let Some(metrics) = &self.metrics {
+            *metrics.lock().unwrap() = sample.clone();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_line_uses_null_for_unknown_values() {
+        let line = json_line(&Sample {
+            cpu: 12.5,
+            rss: 4096,
+            ..Sample::default()
+        });
+        assert!(line.starts_with("{\"elapsed\":0,\"cpu\":12.5,"));
+        assert!(line.contains(",\"rss\":4096,"));
+        assert!(line.contains(",\"swap\":null,"));
+        assert!(line.ends_with(",\"cgroup_memory\":null}"));
+    }
+}
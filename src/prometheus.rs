@@ -0,0 +1,172 @@
+//! Serving the latest sample as Prometheus metrics
+
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::debug;
+use usg::{ProcessSample, Sample};
+
+/// How long a scraper gets to send its request, and to take the response
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Metric = (&'static str, &'static str, fn(&ProcessSample) -> f64);
+
+/// Metrics reported for each monitored PID
+const PROCESS_METRICS: [Metric; 7] = [
+    (
+        "usg_cpu_percent",
+        "CPU usage as a percentage, of one core unless --cpu-mode total",
+        |p| p.cpu,
+    ),
+    ("usg_mem_bytes", "Resident set size", |p| p.rss as f64),
+    ("usg_virtual_mem_bytes", "Virtual memory size", |p| {
+        p.vsz as f64
+    }),
+    ("usg_io_read_bps", "Bytes read per second", |p| p.read_bps),
+    ("usg_io_write_bps", "Bytes written per second", |p| {
+        p.write_bps
+    }),
+    ("usg_threads", "Number of threads", |p| p.threads as f64),
+    ("usg_fds", "Open file descriptors", |p| p.fd_count as f64),
+];
+
+/// Renders `sample` in the Prometheus text format. Traffic is only captured for all of the
+/// sampled processes together, so the network metrics are labeled with every PID. In --system
+/// mode the totals are reported unlabeled
+fn render(sample: &Sample, system: bool) -> String {
+    let system = system.then_some(ProcessSample {
+        pid: 0,
+        cpu: sample.cpu,
+        rss: sample.rss,
+        vsz: sample.vsz,
+        read_bps: sample.read_bps,
+        write_bps: sample.write_bps,
+        threads: sample.threads,
+        fd_count: sample.fd_count,
+    });
+    let mut text = String::new();
+    for (name, help, value) in PROCESS_METRICS {
+        let _ = writeln!(text, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+        for process in &sample.processes {
+            let _ = writeln!(
+                text,
+                "{}{{pid=\"{}\"}} {}",
+                name,
+                process.pid,
+                value(process)
+            );
+        }
+        if let Some(system) = &system {
+            let _ = writeln!(text, "{} {}", name, value(system));
+        }
+    }
+
+    let all_pids: Vec<String> = sample.processes.iter().map(|p| p.pid.to_string()).collect();
+    for (name, help, value) in [
+        (
+            "usg_net_received_bps",
+            "Network bytes received per second",
+            sample.net_received_bps,
+        ),
+        (
+            "usg_net_sent_bps",
+            "Network bytes sent per second",
+            sample.net_sent_bps,
+        ),
+    ] {
+        let _ = writeln!(text, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+        // Left out while traffic isn't being captured
+        match value {
+            Some(value) if system.is_some() => {
+                let _ = writeln!(text, "{} {}", name, value);
+            }
+            Some(value) => {
+                let _ = writeln!(text, "{}{{pid=\"{}\"}} {}", name, all_pids.join(","), value);
+            }
+            None => {}
+        }
+    }
+    text
+}
+
+fn respond(mut stream: TcpStream, body: &str) -> std::io::Result<()> {
+    // Scrapes are served one at a time, so a client that never sends its request mustn't
+    // hold up the ones behind it
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    // Every path gets the metrics, so the request itself doesn't matter
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request)?;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+/// Starts serving whatever sample is in `latest` on `addr`, from a background thread. With
+/// `system`, the sample covers the whole machine rather than some processes
+pub fn serve(addr: SocketAddr, system: bool, latest: Arc<Mutex<Sample>>) -> Result<()> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("Could not listen on {}", addr))?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let body = render(&latest.lock().unwrap(), system);
+            // A scraper hanging up early only affects that scrape
+            if let Err(e) = respond(stream, &body) {
+                debug!("could not serve a scrape: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_labels_each_pid() {
+        let sample = Sample {
+            net_received_bps: Some(0.0),
+            net_sent_bps: Some(10.0),
+            processes: vec![
+                ProcessSample {
+                    pid: 1,
+                    cpu: 12.5,
+                    ..ProcessSample::default()
+                },
+                ProcessSample {
+                    pid: 2,
+                    rss: 4096,
+                    ..ProcessSample::default()
+                },
+            ],
+            ..Sample::default()
+        };
+        let text = render(&sample, false);
+        assert!(text.contains("# TYPE usg_cpu_percent gauge\n"));
+        assert!(text.contains("\nusg_cpu_percent{pid=\"1\"} 12.5\n"));
+        assert!(text.contains("\nusg_mem_bytes{pid=\"2\"} 4096\n"));
+        assert!(text.contains("\nusg_net_sent_bps{pid=\"1,2\"} 10\n"));
+    }
+
+    #[test]
+    fn render_leaves_the_system_totals_unlabeled() {
+        let sample = Sample {
+            rss: 8192,
+            net_received_bps: Some(5.0),
+            ..Sample::default()
+        };
+        let text = render(&sample, true);
+        assert!(text.contains("\nusg_mem_bytes 8192\n"));
+        assert!(text.contains("\nusg_net_received_bps 5\n"));
+    }
+}
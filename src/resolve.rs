@@ -0,0 +1,118 @@
+//! Reverse DNS lookups for `--resolve`, without holding up sampling
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::CStr;
+use std::mem;
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest the lookups started for a sample may delay it, all of them together. Slower ones
+/// finish in the background and show up in a later sample
+const TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Remembers the hostname of every address it has looked up
+pub struct Resolver {
+    names: HashMap<IpAddr, Option<String>>,
+    pending: HashSet<IpAddr>,
+    sender: Sender<(IpAddr, Option<String>)>,
+    results: Receiver<(IpAddr, Option<String>)>,
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        let (sender, results) = mpsc::channel();
+        Resolver {
+            names: HashMap::new(),
+            pending: HashSet::new(),
+            sender,
+            results,
+        }
+    }
+
+    /// Looks up the addresses it hasn't seen yet, all at once, waiting for them up to
+    /// [`TIMEOUT`] in total. Lookups started earlier aren't waited for again
+    pub fn resolve(&mut self, ips: impl IntoIterator<Item = IpAddr>) {
+        let mut started = Vec::new();
+        for ip in ips {
+            if !self.names.contains_key(&ip) && self.pending.insert(ip) {
+                let sender = self.sender.clone();
+                thread::spawn(move || sender.send((ip, reverse_lookup(ip))));
+                started.push(ip);
+            }
+        }
+
+        let deadline = Instant::now() + TIMEOUT;
+        while !started.is_empty() {
+            match self
+                .results
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok((ip, name)) => self.finish(ip, name),
+                Err(_) => break,
+            }
+            started.retain(|ip| self.pending.contains(ip));
+        }
+        while let Ok((ip, name)) = self.results.try_recv() {
+            self.finish(ip, name);
+        }
+    }
+
+    fn finish(&mut self, ip: IpAddr, name: Option<String>) {
+        self.pending.remove(&ip);
+        self.names.insert(ip, name);
+    }
+
+    /// The hostname of `ip`, or `None` if it has none or its lookup hasn't finished yet
+    pub fn name(&self, ip: IpAddr) -> Option<&str> {
+        self.names.get(&ip)?.as_deref()
+    }
+}
+
+// std can only resolve names to addresses, so go through getnameinfo for the reverse
+fn reverse_lookup(ip: IpAddr) -> Option<String> {
+    // SAFETY: sockaddr_storage is valid when zeroed, and large enough for either address family
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match ip {
+        IpAddr::V4(v4) => {
+            let addr = &mut storage as *mut _ as *mut libc::sockaddr_in;
+            // SAFETY: addr points into storage, which outlives it
+            unsafe {
+                (*addr).sin_family = libc::AF_INET as libc::sa_family_t;
+                (*addr).sin_addr.s_addr = u32::from_ne_bytes(v4.octets());
+            }
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        IpAddr::V6(v6) => {
+            let addr = &mut storage as *mut _ as *mut libc::sockaddr_in6;
+            // SAFETY: as above
+            unsafe {
+                (*addr).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                (*addr).sin6_addr.s6_addr = v6.octets();
+            }
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    let mut host = [0 as libc::c_char; libc::NI_MAXHOST as usize];
+    // SAFETY: the address and host buffers are valid for the lengths passed. NI_NAMEREQD makes
+    // it fail rather than fill in the numeric address when there is no name
+    let result = unsafe {
+        libc::getnameinfo(
+            &storage as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    // SAFETY: getnameinfo succeeded, so host holds a NUL-terminated string
+    let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
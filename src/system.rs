@@ -0,0 +1,73 @@
+//! Measuring the whole machine instead of a set of processes
+
+use std::fs;
+
+use anyhow::{Context, Result};
+use procfs::{CpuTime, KernelStats, LoadAverage, Meminfo};
+
+use crate::disk::DiskCounters;
+use crate::{counter_delta, Usage};
+
+// Ticks spent running something, in user and kernel mode. Guest time is already part of user
+// time, and idle, iowait and steal are all time this machine wasn't running anything
+fn busy_ticks(cpu: &CpuTime) -> (u64, u64) {
+    let user = cpu.user + cpu.nice;
+    let system = cpu.system + cpu.irq.unwrap_or(0) + cpu.softirq.unwrap_or(0);
+    (user, system)
+}
+
+// Memory in use, the way `free` counts it. Kernels before 3.14 don't estimate what's
+// available, so the page cache and buffers are assumed to be reclaimable
+fn used_memory(meminfo: &Meminfo) -> u64 {
+    let available = meminfo
+        .mem_available
+        .unwrap_or(meminfo.mem_free + meminfo.buffers + meminfo.cached);
+    meminfo.mem_total.saturating_sub(available)
+}
+
+// The file handles allocated across the system, the first field of /proc/sys/fs/file-nr
+fn open_files() -> Result<usize> {
+    let text = fs::read_to_string("/proc/sys/fs/file-nr")?;
+    let field = text.split_whitespace().next().unwrap_or_default();
+    field
+        .parse()
+        .with_context(|| format!("Invalid /proc/sys/fs/file-nr: {}", text.trim()))
+}
+
+/// The machine's CPU and disk counters, as of the previous sample
+pub(crate) struct SystemCounters {
+    prev_cpu: CpuTime,
+    disks: DiskCounters,
+}
+
+impl SystemCounters {
+    pub fn new() -> Result<SystemCounters> {
+        Ok(SystemCounters {
+            prev_cpu: KernelStats::new()?.total,
+            disks: DiskCounters::new()?,
+        })
+    }
+
+    /// What the whole machine used since the previous sample, and how many files are open
+    pub fn sample(&mut self, elapsed: f64) -> Result<(Usage, usize)> {
+        let cpu = KernelStats::new()?.total;
+        let (user, system) = busy_ticks(&cpu);
+        let (prev_user, prev_system) = busy_ticks(&self.prev_cpu);
+        self.prev_cpu = cpu;
+
+        let meminfo = Meminfo::new()?;
+        let disks = self.disks.sample_physical(elapsed)?;
+        let usage = Usage {
+            user_ticks: counter_delta(user, prev_user),
+            system_ticks: counter_delta(system, prev_system),
+            rss: used_memory(&meminfo),
+            vsz: meminfo.committed_as,
+            swap: Some(meminfo.swap_total.saturating_sub(meminfo.swap_free)),
+            bytes_read: disks.iter().map(|disk| disk.bytes_read).sum(),
+            bytes_written: disks.iter().map(|disk| disk.bytes_written).sum(),
+            threads: LoadAverage::new()?.max.into(),
+            ..Usage::default()
+        };
+        Ok((usage, open_files()?))
+    }
+}
@@ -0,0 +1,223 @@
+//! A full-screen view of a [`Monitor`], redrawn every interval
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::LevelFilter;
+use procfs::Meminfo;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, Borders, Gauge, Sparkline};
+use ratatui::{DefaultTerminal, Frame};
+use usg::{Monitor, ProcessExited, Sample, Summary};
+
+use crate::output::Outputs;
+
+/// Number of CPU readings kept for the sparkline
+const HISTORY: usize = 256;
+
+/// What has been seen so far, used to scale the gauges
+#[derive(Default)]
+struct View {
+    sample: Sample,
+    cpu_history: VecDeque<u64>,
+    cpu_max: f64,
+    mem_total: u64,
+    io_peak: f64,
+    net_peak: f64,
+    status: Option<String>,
+}
+
+impl View {
+    fn update(&mut self, sample: Sample) {
+        if self.cpu_history.len() == HISTORY {
+            self.cpu_history.pop_front();
+        }
+        self.cpu_history.push_back(sample.cpu.round() as u64);
+        self.io_peak = self.io_peak.max(sample.read_bps + sample.write_bps);
+        self.net_peak = self.net_peak.max(sample.net_bps().unwrap_or(0.0));
+        self.sample = sample;
+    }
+}
+
+/// Fraction of `max` that `value` fills, or 0 before there is a maximum
+fn ratio(value: f64, max: f64) -> f64 {
+    if max > 0.0 {
+        (value / max).clamp(0.0, 1.0)
+    } else {
+        0.0
+    }
+}
+
+fn gauge(title: &str, ratio: f64, label: String, color: Color) -> Gauge<'_> {
+    Gauge::default()
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .gauge_style(Style::default().fg(color))
+        .ratio(ratio)
+        .label(label)
+}
+
+fn draw(frame: &mut Frame, view: &View) {
+    let sample = &view.sample;
+    let [cpu, mem, io, net, history, status] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Min(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    frame.render_widget(
+        gauge(
+            "CPU",
+            ratio(sample.cpu, view.cpu_max),
+            format!("{:.1}%", sample.cpu),
+            Color::Green,
+        ),
+        cpu,
+    );
+    frame.render_widget(
+        gauge(
+            "Memory",
+            ratio(sample.rss as f64, view.mem_total as f64),
+            format!("RSS {}B of {}B", sample.rss, view.mem_total),
+            Color::Magenta,
+        ),
+        mem,
+    );
+    frame.render_widget(
+        gauge(
+            "I/O",
+            ratio(sample.read_bps + sample.write_bps, view.io_peak),
+            format!(
+                "read {:.0}B/s written {:.0}B/s",
+                sample.read_bps, sample.write_bps
+            ),
+            Color::Yellow,
+        ),
+        io,
+    );
+    frame.render_widget(
+        gauge(
+            "Network",
+            ratio(sample.net_bps().unwrap_or(0.0), view.net_peak),
+            match (sample.net_received_bps, sample.net_sent_bps) {
+                (Some(received), Some(sent)) => format!("↓{:.0}B/s ↑{:.0}B/s", received, sent),
+                _ => String::from("N/A"),
+            },
+            Color::Cyan,
+        ),
+        net,
+    );
+
+    // Show the most recent readings that fit inside the borders
+    let width = usize::from(history.width.saturating_sub(2));
+    let skip = view.cpu_history.len().saturating_sub(width);
+    let data: Vec<u64> = view.cpu_history.iter().skip(skip).copied().collect();
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::default().title("CPU history").borders(Borders::ALL))
+            .style(Style::default().fg(Color::Green))
+            .data(&data),
+        history,
+    );
+
+    let status_line = view
+        .status
+        .clone()
+        .unwrap_or_else(|| format!("{:.1}s elapsed, press q to quit", sample.elapsed));
+    frame.render_widget(status_line, status);
+}
+
+/// Waits up to `timeout` for a key press, returning whether it asked to quit
+fn quit_requested(timeout: Duration) -> Result<bool> {
+    if !event::poll(timeout)? {
+        return Ok(false);
+    }
+    match event::read()? {
+        Event::Key(key) if key.kind == KeyEventKind::Press => Ok(match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => true,
+            // Raw mode delivers Ctrl-C as a key rather than a signal
+            KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+            _ => false,
+        }),
+        _ => Ok(false),
+    }
+}
+
+fn run_loop(
+    terminal: &mut DefaultTerminal,
+    monitor: &mut Monitor,
+    interval: f64,
+    duration: Option<f64>,
+    summary: &mut Summary,
+    outputs: &mut Outputs,
+) -> Result<()> {
+    let interval = Duration::from_secs_f64(interval);
+    let mut view = View {
+        cpu_max: monitor.cpu_max(),
+        mem_total: Meminfo::new()?.mem_total,
+        ..View::default()
+    };
+    let mut next_sample = Instant::now() + interval;
+    let mut running = true;
+
+    loop {
+        terminal.draw(|frame| draw(frame, &view))?;
+
+        let timeout = if running {
+            next_sample.saturating_duration_since(Instant::now())
+        } else {
+            interval
+        };
+        if quit_requested(timeout)? {
+            return Ok(());
+        }
+        if !running || Instant::now() < next_sample {
+            continue;
+        }
+        next_sample += interval;
+
+        match monitor.sample() {
+            Ok(sample) => {
+                summary.record(&sample);
+                outputs.record(&sample)?;
+                view.update(sample);
+            }
+            Err(e) if e.is::<ProcessExited>() => {
+                // Leave the last reading up until the user quits
+                view.status = Some(format!("{}, press q to quit", e));
+                running = false;
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+
+        if duration.is_some_and(|limit| summary.elapsed >= limit) {
+            return Ok(());
+        }
+    }
+}
+
+/// Shows live gauges until `q` is pressed, restoring the terminal afterwards
+pub fn run(
+    monitor: &mut Monitor,
+    interval: f64,
+    duration: Option<f64>,
+    summary: &mut Summary,
+    outputs: &mut Outputs,
+) -> Result<()> {
+    // Anything logged to stderr would be drawn over the view, and the capture threads can log
+    // at any time, so logging is off until the terminal is restored
+    let log_level = log::max_level();
+    log::set_max_level(LevelFilter::Off);
+    let mut terminal = ratatui::try_init()?;
+    let result = run_loop(&mut terminal, monitor, interval, duration, summary, outputs);
+    ratatui::restore();
+    log::set_max_level(log_level);
+    result
+}